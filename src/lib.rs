@@ -0,0 +1,2520 @@
+// Copyright (C) 2023 Stephane Raux. Distributed under the 0BSD license.
+
+//! # Overview
+//! - [⚖ 0BSD license](https://spdx.org/licenses/0BSD.html)
+//!
+//! CLI tool to clean Rust build artifacts.
+//!
+//! The cleaning logic is also available as a library through [`clean`], or through
+//! [`find_targets`] and [`remove_target`] to decide what to remove.
+//!
+//! # Features
+//! - `tracing`: Reports cleaning events through [`tracing`](https://docs.rs/tracing). The CLI
+//!   then logs them to stderr according to `RUST_LOG`.
+//! - `trash`: Allows moving build artifacts to the trash instead of deleting them, through
+//!   [`trash`](https://docs.rs/trash).
+//! - `testing`: Allows replacing how directories are deleted through `CleanOptions::remover`,
+//!   to test error handling deterministically. It is meant for tests only.
+//!
+//! # Contribute
+//! All contributions shall be licensed under the [0BSD license](https://spdx.org/licenses/0BSD.html).
+
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::{gitignore::Gitignore, Match};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs::{
+        canonicalize, metadata, read_dir, read_to_string, remove_dir_all, DirEntry, File,
+        FileTimes, Metadata, TryLockError,
+    },
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Options controlling what gets cleaned and how
+#[derive(Clone, Debug)]
+pub struct CleanOptions {
+    /// Number of worker threads scanning directories
+    ///
+    /// Defaults to twice the available parallelism, or 8 if it cannot be determined. Workers
+    /// spend most of their time waiting on the file system, so more workers than cores keeps
+    /// the cores busy. At least one worker runs even if this is 0.
+    pub scan_worker_count: usize,
+    /// Number of worker threads removing directories, defaulting like `scan_worker_count`
+    pub remove_worker_count: usize,
+    /// Report what would be removed without removing anything
+    pub dry_run: bool,
+    /// Measure the disk space freed by removing build artifacts
+    pub measure: bool,
+    /// Only remove build artifacts not modified for this long
+    pub older_than: Option<Duration>,
+    /// Only remove build artifacts not accessed for this long
+    ///
+    /// Access times may be stale, e.g. on file systems mounted with `noatime`, in which case a
+    /// warning is reported.
+    pub accessed_before: Option<Duration>,
+    /// Leave in place build artifacts modified after the `Cargo.lock` of their package, or its
+    /// manifest if there is no lock file, as they likely come from a build still relevant
+    pub exclude_newer_than_manifest: bool,
+    /// Paths to skip, matched relative to the scanned directory
+    pub exclude: GlobSet,
+    /// Package directories to leave alone, matched relative to the scanned directory
+    ///
+    /// Their build artifacts are left in place and their subdirectories are not scanned.
+    pub exclude_projects: GlobSet,
+    /// Only remove build artifact directories whose path, relative to the scanned directory,
+    /// matches one of these patterns, or all of them if empty
+    ///
+    /// Directories that cannot hold matching paths, judging from the literal leading
+    /// components of the patterns, are not scanned.
+    pub only: Vec<Glob>,
+    /// Do not descend into directories ignored by `.gitignore` files
+    ///
+    /// Target directories are still removed even though they are usually ignored.
+    pub respect_gitignore: bool,
+    /// Maximum depth of directories to scan, 0 meaning only the given directory
+    pub max_depth: Option<usize>,
+    /// Scan symlinked directories and remove what symlinked target directories point to
+    pub follow_symlinks: bool,
+    /// Run `cargo clean` in the package directory instead of removing the target directory
+    ///
+    /// If `cargo` cannot be found, the target directory is removed instead. Other build
+    /// artifact directories are always removed directly.
+    pub cargo_clean: bool,
+    /// Target directory shared by packages, e.g. from `CARGO_TARGET_DIR`
+    ///
+    /// It is removed once if any package is found.
+    pub target_dir: Option<PathBuf>,
+    /// Remove target directories even if a build currently holds a lock in them
+    pub ignore_locks: bool,
+    /// Leave target directories smaller than this many bytes in place
+    ///
+    /// Target directories whose size cannot be determined are removed.
+    pub min_size: Option<u64>,
+    /// Flag to set to stop cleaning early
+    ///
+    /// Workers check it between jobs, so removals in progress are completed.
+    pub stop: Option<Arc<AtomicBool>>,
+    /// Number of times to retry removals failing with transient errors
+    ///
+    /// The delay before each retry doubles, starting at 100ms.
+    pub retries: u32,
+    /// Names of directories holding build artifacts next to a manifest, besides `target`
+    pub extra_dirs: Vec<String>,
+    /// Name of a file next to a manifest holding the path of a directory of build artifacts to
+    /// remove, relative to the package directory
+    pub target_marker: Option<String>,
+    /// Only remove the subdirectory of target directories for this profile, e.g. `debug`
+    ///
+    /// Build artifacts shared by profiles are then left in place.
+    pub profile: Option<String>,
+    /// Only remove the target directories needed to free this many bytes, the largest and least
+    /// recently accessed first
+    ///
+    /// Nothing is removed until scanning completes, and sizes are measured.
+    pub reclaim: Option<u64>,
+    /// Leave in place this many of the most recently modified target directories found
+    ///
+    /// Nothing is removed until scanning completes.
+    pub keep_recent: Option<usize>,
+    /// Remove the largest target directories first, e.g. to meet `reclaim` in fewer removals
+    ///
+    /// Nothing is removed until scanning completes, and sizes are measured.
+    pub biggest_first: bool,
+    /// Maximum number of directories waiting to be scanned or removed, unlimited if `None`
+    ///
+    /// Scanners blocked by a full queue scan directories themselves, and removal is applied
+    /// backpressure, which bounds memory use on huge trees.
+    pub queue_bound: Option<usize>,
+    /// Leave empty target directories in place
+    pub skip_empty: bool,
+    /// Only remove build artifacts of packages with these names, or of all packages if empty
+    pub packages: Vec<String>,
+    /// Only remove build artifacts of packages without changes since this git revision
+    ///
+    /// Packages outside a git repository are left in place.
+    pub unchanged_since: Option<String>,
+    /// Stop cleaning once this instant is reached, letting ongoing removals finish
+    ///
+    /// Being an instant rather than a duration lets successive calls to [`clean`] share it.
+    pub deadline: Option<Instant>,
+    /// Only remove build artifacts of packages in git repositories
+    ///
+    /// Repositories are only looked for at and below the scanned directories, so that one
+    /// enclosing them, e.g. of dotfiles in the home directory, does not count.
+    pub git_only: bool,
+    /// Name of the manifest file identifying packages, `Cargo.toml` by default
+    pub manifest_name: String,
+    /// Order in which directories are scanned
+    pub order: ScanOrder,
+    /// Only let the observer decline removing directories larger than this many bytes, removing
+    /// smaller ones without asking
+    ///
+    /// Sizes are then measured.
+    pub confirm_over: Option<u64>,
+    /// List the build artifact directories removed in [`Summary::removed_paths`]
+    ///
+    /// This is opt-in as the list can grow large.
+    pub collect_removed: bool,
+    /// Only remove target directories making up at least this fraction, between 0 and 1, of the
+    /// size of their package including them
+    ///
+    /// Target directories of packages whose size cannot be determined are removed.
+    pub target_ratio: Option<f64>,
+    /// Also scan hidden directories, whose name starts with a dot, e.g. `.git`
+    ///
+    /// Hidden build artifact directories and roots are handled either way.
+    pub include_hidden: bool,
+    /// Leave the target directories of workspace roots in place, removing those of members
+    ///
+    /// Members are then scanned for target directories of their own.
+    pub keep_workspace_root: bool,
+    /// Also scan workspace members, e.g. nested workspaces, removing target directories they
+    /// hold of their own
+    ///
+    /// Otherwise, the target directory of a workspace root is the only one removed for its
+    /// members. Packages that are not members are scanned either way.
+    pub nested: bool,
+    /// Stop cleaning at the first error, letting ongoing removals finish
+    pub fail_fast: bool,
+    /// Check that build artifact directories no longer exist once removed, reporting an error
+    /// otherwise, as some network file systems report success early
+    ///
+    /// Lingering directories count as transient failures, retried according to `retries`.
+    pub verify: bool,
+    /// Count build artifact directories that vanish before being deleted as removed instead of
+    /// reporting an error, as happens when another process cleans the same directories
+    pub ignore_missing: bool,
+    /// Record the time taken to read each directory, listing this many of the slowest in
+    /// [`Summary::slowest_dirs`]
+    pub slowest_dirs: Option<usize>,
+    /// How to measure file sizes
+    pub size_mode: SizeMode,
+    /// Leave in place the build artifacts of packages pinned to a toolchain by a
+    /// `rust-toolchain` or `rust-toolchain.toml` file, as rebuilding them can be costly
+    pub keep_pinned: bool,
+    /// Leave build artifact directories in place once the file system holding them has this
+    /// many bytes available, checked before each removal
+    ///
+    /// If available space cannot be determined, a warning is reported and removals proceed.
+    pub min_free_space: Option<u64>,
+    /// Move build artifacts to the trash instead of deleting them
+    ///
+    /// Directories that cannot be moved to the trash are deleted with a warning, unless
+    /// `strict_trash` is true.
+    #[cfg(feature = "trash")]
+    pub trash: bool,
+    /// Fail instead of deleting directories that cannot be moved to the trash
+    #[cfg(feature = "trash")]
+    pub strict_trash: bool,
+    /// Function deleting a build artifact directory and everything in it, [`remove_target`] by
+    /// default
+    ///
+    /// This is meant for tests only, e.g. to make removals of chosen directories fail.
+    ///
+    /// ```
+    /// use cleanrust::{clean, remove_target, CleanOptions, FnObserver};
+    /// use std::{fs, io, path::Path};
+    ///
+    /// fn failing_in_broken(path: &Path) -> Result<(), io::Error> {
+    ///     if path.components().any(|component| component.as_os_str() == "broken") {
+    ///         Err(io::Error::other("simulated failure"))
+    ///     } else {
+    ///         remove_target(path)
+    ///     }
+    /// }
+    ///
+    /// let dir = std::env::temp_dir().join("cleanrust-remover");
+    /// for package in ["broken/a", "b"] {
+    ///     fs::create_dir_all(dir.join(package).join("target"))?;
+    ///     fs::write(dir.join(package).join("Cargo.toml"), "")?;
+    /// }
+    /// let options = CleanOptions {
+    ///     remover: failing_in_broken,
+    ///     ..CleanOptions::default()
+    /// };
+    /// let summary = clean(&[&dir], &options, FnObserver::new());
+    /// assert_eq!((summary.removed, summary.errors), (1, 1));
+    /// assert!(dir.join("broken/a/target").exists());
+    /// fs::remove_dir_all(&dir)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    ///
+    /// Sizes are measured once however many attempts removals take, which a remover adding a
+    /// file before failing shows:
+    ///
+    /// ```
+    /// use cleanrust::{clean, remove_target, CleanOptions, FnObserver};
+    /// use std::{
+    ///     fs, io,
+    ///     path::Path,
+    ///     sync::atomic::{AtomicBool, Ordering},
+    /// };
+    ///
+    /// static FAILED: AtomicBool = AtomicBool::new(false);
+    ///
+    /// fn failing_once(path: &Path) -> Result<(), io::Error> {
+    ///     if FAILED.swap(true, Ordering::SeqCst) {
+    ///         remove_target(path)
+    ///     } else {
+    ///         fs::write(path.join("late"), [0; 100])?;
+    ///         Err(io::ErrorKind::TimedOut.into())
+    ///     }
+    /// }
+    ///
+    /// let dir = std::env::temp_dir().join("cleanrust-retried-remover");
+    /// fs::create_dir_all(dir.join("target"))?;
+    /// fs::write(dir.join("Cargo.toml"), "")?;
+    /// fs::write(dir.join("target/artifact"), [0; 10])?;
+    /// let options = CleanOptions {
+    ///     remover: failing_once,
+    ///     retries: 1,
+    ///     measure: true,
+    ///     ..CleanOptions::default()
+    /// };
+    /// let summary = clean(&[&dir], &options, FnObserver::new());
+    /// assert_eq!((summary.removed, summary.freed_bytes), (1, Some(10)));
+    /// fs::remove_dir_all(&dir)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    #[cfg(feature = "testing")]
+    pub remover: fn(&Path) -> Result<(), io::Error>,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        Self {
+            scan_worker_count: default_worker_count(),
+            remove_worker_count: default_worker_count(),
+            dry_run: false,
+            measure: false,
+            older_than: None,
+            accessed_before: None,
+            exclude_newer_than_manifest: false,
+            exclude: GlobSet::empty(),
+            exclude_projects: GlobSet::empty(),
+            only: Vec::new(),
+            respect_gitignore: false,
+            max_depth: None,
+            follow_symlinks: false,
+            cargo_clean: false,
+            target_dir: None,
+            ignore_locks: false,
+            min_size: None,
+            stop: None,
+            retries: 0,
+            keep_recent: None,
+            biggest_first: false,
+            reclaim: None,
+            profile: None,
+            extra_dirs: Vec::new(),
+            target_marker: None,
+            queue_bound: None,
+            skip_empty: false,
+            packages: Vec::new(),
+            unchanged_since: None,
+            deadline: None,
+            git_only: false,
+            manifest_name: "Cargo.toml".to_owned(),
+            order: ScanOrder::BreadthFirst,
+            confirm_over: None,
+            collect_removed: false,
+            target_ratio: None,
+            include_hidden: false,
+            keep_workspace_root: false,
+            nested: false,
+            fail_fast: false,
+            verify: false,
+            ignore_missing: false,
+            slowest_dirs: None,
+            size_mode: SizeMode::Apparent,
+            keep_pinned: false,
+            min_free_space: None,
+            #[cfg(feature = "trash")]
+            trash: false,
+            #[cfg(feature = "trash")]
+            strict_trash: false,
+            #[cfg(feature = "testing")]
+            remover: remove_target,
+        }
+    }
+}
+
+/// Order in which directories are scanned, and thus build artifacts found
+///
+/// The order is best-effort since workers scan concurrently.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ScanOrder {
+    /// Directories closer to the roots first
+    #[default]
+    BreadthFirst,
+    /// Subdirectories before siblings, completing deep trees first
+    DepthFirst,
+}
+
+/// How to measure the size of files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SizeMode {
+    /// Number of bytes in files, like `du --apparent-size`
+    #[default]
+    Apparent,
+    /// Disk space allocated to files, like `du`, which differs for sparse files and partially
+    /// used blocks
+    ///
+    /// This is only available on Unix, apparent sizes being measured elsewhere with a warning.
+    Disk,
+}
+
+/// Returns twice the available parallelism, or 8 if it cannot be determined
+fn default_worker_count() -> usize {
+    thread::available_parallelism().map_or(8, |parallelism| parallelism.get() * 2)
+}
+
+/// Counts of what happened while cleaning
+///
+/// In a dry run, `removed` and `freed_bytes` count what would have been removed and freed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    /// Number of directories scanned
+    pub scanned: usize,
+    /// Number of build artifact directories removed
+    pub removed: usize,
+    /// Number of build artifact directories left in place
+    pub skipped: usize,
+    /// Number of build artifact directories left in place because a build was using them
+    pub locked: usize,
+    /// Number of build artifact directories left in place because their package is pinned to a
+    /// toolchain
+    pub pinned: usize,
+    /// Number of errors
+    pub errors: usize,
+    /// Number of bytes freed, if measured, including what removals failing partway freed
+    pub freed_bytes: Option<u64>,
+    /// Number of files removed, if measured
+    pub removed_files: Option<u64>,
+    /// Number of directories removed within the build artifact directories, if measured
+    pub removed_dirs: Option<u64>,
+    /// Whether cleaning was stopped early through [`CleanOptions::stop`]
+    pub interrupted: bool,
+    /// Whether cleaning was stopped early because [`CleanOptions::deadline`] was reached
+    pub timed_out: bool,
+    /// Number of build artifact directories found but left in place because cleaning stopped
+    /// early
+    pub unprocessed: usize,
+    /// Whether [`CleanOptions::min_free_space`] bytes were found available
+    pub free_space_reached: bool,
+    /// Number of directories to clean that could not be read, also counted in `errors`
+    pub unreadable_roots: usize,
+    /// Number of errors while scanning rather than removing, also counted in `errors`
+    pub scan_errors: usize,
+    /// Time spent by workers
+    pub timing: Timing,
+    /// Directories that took the longest to read, slowest first, if requested through
+    /// [`CleanOptions::slowest_dirs`]
+    pub slowest_dirs: Vec<DirTiming>,
+    /// Build artifact directories removed, in no particular order, if requested through
+    /// [`CleanOptions::collect_removed`]
+    ///
+    /// # Example
+    /// ```
+    /// use cleanrust::{clean, CleanOptions, FnObserver};
+    /// use std::fs;
+    ///
+    /// let dir = std::env::temp_dir().join("cleanrust-removed-paths");
+    /// for package in ["a", "b"] {
+    ///     fs::create_dir_all(dir.join(package).join("target"))?;
+    ///     fs::write(dir.join(package).join("Cargo.toml"), "")?;
+    /// }
+    /// let options = CleanOptions {
+    ///     collect_removed: true,
+    ///     ..Default::default()
+    /// };
+    /// let mut removed = clean(&[&dir], &options, FnObserver::new()).removed_paths;
+    /// removed.sort();
+    /// assert_eq!(removed, [dir.join("a/target"), dir.join("b/target")]);
+    /// fs::remove_dir_all(&dir)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub removed_paths: Vec<PathBuf>,
+}
+
+/// Time taken to read a directory
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct DirTiming {
+    /// Time spent reading the entries of the directory
+    pub duration: Duration,
+    /// Number of entries in the directory
+    pub entries: usize,
+    /// Path of the directory
+    pub path: PathBuf,
+}
+
+/// Time spent by workers, summed over all the workers of each pool
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timing {
+    /// Time spent scanning
+    pub scan_busy: Duration,
+    /// Time spent by scanners waiting for directories to scan
+    pub scan_idle: Duration,
+    /// Time spent removing
+    pub remove_busy: Duration,
+    /// Time spent by removers waiting for directories to remove
+    pub remove_idle: Duration,
+}
+
+impl Summary {
+    /// Returns whether cleaning completed without errors
+    pub fn is_success(&self) -> bool {
+        self.errors == 0 && !self.interrupted
+    }
+}
+
+/// Scans `roots` recursively and removes the build artifacts found
+///
+/// Roots nested in other roots are skipped. `observer` is notified of progress from the worker
+/// threads as cleaning proceeds.
+pub fn clean<P, O>(roots: &[P], options: &CleanOptions, observer: O) -> Summary
+where
+    P: AsRef<Path>,
+    O: Observer + Sync,
+{
+    let cleaner = Cleaner {
+        roots: outermost(roots),
+        options,
+        only: options.only.iter().map(OnlyPattern::new).collect(),
+        observer: Tally::new(observer, options),
+        scanned_dirs: Default::default(),
+        queued_targets: Default::default(),
+        candidates: Default::default(),
+        unreadable_roots: Default::default(),
+        scan_errors: Default::default(),
+        atime_checks: Default::default(),
+        stale_atimes: Default::default(),
+        unprocessed: Default::default(),
+        free_space_reached: Default::default(),
+        free_space_unknown: Default::default(),
+        scan_times: Default::default(),
+        remove_times: Default::default(),
+        slowest_dirs: Default::default(),
+    };
+    // Ranking targets requires finding them all before removing any.
+    let defer_removals =
+        options.keep_recent.is_some() || options.reclaim.is_some() || options.biggest_first;
+    #[cfg(not(unix))]
+    if options.size_mode == SizeMode::Disk {
+        cleaner.observer.on_warning(
+            cleaner.roots.first().copied().unwrap_or(Path::new(".")),
+            "Disk usage is only available on Unix, measuring apparent sizes instead",
+        );
+    }
+    cleaner.run(&cleaner.roots, Vec::new(), defer_removals);
+    if defer_removals {
+        let candidates = std::mem::take(&mut *cleaner.candidates.lock().unwrap());
+        let removals = cleaner.select(candidates);
+        cleaner.run(&[], removals, false);
+    }
+    cleaner.warn_stale_atimes();
+    let mut summary = cleaner.observer.summary();
+    summary.interrupted = cleaner.is_interrupted();
+    summary.timed_out = !summary.interrupted && cleaner.is_timed_out();
+    summary.unprocessed = cleaner.unprocessed.load(atomic::Ordering::SeqCst);
+    summary.free_space_reached = cleaner.free_space_reached.load(atomic::Ordering::SeqCst);
+    summary.timing = Timing {
+        scan_busy: cleaner.scan_times.busy(),
+        scan_idle: cleaner.scan_times.idle(),
+        remove_busy: cleaner.remove_times.busy(),
+        remove_idle: cleaner.remove_times.idle(),
+    };
+    summary.slowest_dirs = cleaner
+        .slowest_dirs
+        .into_inner()
+        .unwrap()
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(timing)| timing)
+        .collect();
+    summary.unreadable_roots = cleaner.unreadable_roots.load(atomic::Ordering::SeqCst);
+    summary.scan_errors = cleaner.scan_errors.load(atomic::Ordering::SeqCst);
+    summary
+}
+
+/// Returns the build artifact directories that cleaning `root` would remove, as they are found
+///
+/// This performs a dry run of [`clean`] in the background with the given options. Errors are
+/// yielded along the way, with the path they relate to in their message. If
+/// [`CleanOptions::stop`] is not set, dropping the iterator stops scanning.
+///
+/// # Example
+/// ```
+/// use cleanrust::{find_targets, remove_target, CleanOptions};
+/// use std::fs;
+///
+/// let dir = std::env::temp_dir().join("cleanrust-find-targets");
+/// for package in ["a", "b"] {
+///     fs::create_dir_all(dir.join(package).join("target"))?;
+///     fs::write(dir.join(package).join("Cargo.toml"), "")?;
+/// }
+/// for candidate in find_targets(&dir, &CleanOptions::default()) {
+///     let candidate = candidate?;
+///     if candidate.package_dir.ends_with("a") {
+///         remove_target(&candidate.path)?;
+///     }
+/// }
+/// assert!(!dir.join("a/target").exists());
+/// assert!(dir.join("b/target").exists());
+/// fs::remove_dir_all(&dir)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn find_targets(
+    root: &Path,
+    options: &CleanOptions,
+) -> impl Iterator<Item = Result<TargetCandidate, io::Error>> {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let stop = options
+        .stop
+        .is_none()
+        .then(|| Arc::new(AtomicBool::new(false)));
+    let options = CleanOptions {
+        dry_run: true,
+        stop: options.stop.clone().or_else(|| stop.clone()),
+        ..options.clone()
+    };
+    let root = root.to_owned();
+    thread::spawn(move || clean(&[root], &options, CandidateSender { sender, stop }));
+    receiver.into_iter()
+}
+
+/// Build artifact directory found by [`find_targets`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TargetCandidate {
+    /// Path of the build artifact directory
+    pub path: PathBuf,
+    /// Directory of the package the build artifacts belong to
+    pub package_dir: PathBuf,
+    /// Number of bytes the directory holds, if measured through [`CleanOptions::measure`]
+    pub size: Option<u64>,
+}
+
+/// Removes the build artifact directory at `path`, e.g. found by [`find_targets`]
+pub fn remove_target(path: &Path) -> Result<(), io::Error> {
+    remove_tree(path)
+}
+
+/// Observer sending the directories a dry run would remove, along with errors, to
+/// [`find_targets`]
+struct CandidateSender {
+    sender: Sender<Result<TargetCandidate, io::Error>>,
+    /// Flag stopping the dry run once nothing receives candidates anymore, if owned
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl CandidateSender {
+    fn send(&self, item: Result<TargetCandidate, io::Error>) {
+        if self.sender.send(item).is_err() {
+            if let Some(stop) = &self.stop {
+                stop.store(true, atomic::Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Observer for CandidateSender {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        self.send(Err(io::Error::new(
+            e.kind(),
+            format!("{}: {e}", path.display()),
+        )))
+    }
+
+    fn on_removal(&self, _: &Path, _: &Path, _: Option<u64>) {}
+
+    fn on_scanned(&self, _: &Path) {}
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.send(Ok(TargetCandidate {
+            path: path.to_owned(),
+            package_dir: package_dir.to_owned(),
+            size,
+        }))
+    }
+
+    fn on_warning(&self, _: &Path, _: &str) {}
+
+    fn on_skipped(&self, _: &Path, _: SkipReason) {}
+}
+
+/// What is known about a directory while handling its entries
+#[derive(Clone, Copy)]
+struct EntryContext<'a> {
+    /// Whether the directory holds a manifest
+    is_package: bool,
+    /// Whether to scan subdirectories
+    descend: bool,
+    /// Directory named by a target marker file, which is not scanned
+    marked_target: Option<&'a Path>,
+}
+
+/// Pattern from [`CleanOptions::only`]
+struct OnlyPattern {
+    matcher: GlobMatcher,
+    /// Leading components of the pattern without wildcards, which matching paths start with
+    prefix: PathBuf,
+}
+
+impl OnlyPattern {
+    fn new(glob: &Glob) -> Self {
+        let prefix = glob
+            .glob()
+            .split('/')
+            .take_while(|component| !component.contains(['*', '?', '[', '{', '\\']))
+            .collect();
+        Self {
+            matcher: glob.compile_matcher(),
+            prefix,
+        }
+    }
+}
+
+/// Context shared by the workers cleaning a directory tree
+struct Cleaner<'a, O> {
+    roots: Vec<&'a Path>,
+    options: &'a CleanOptions,
+    /// Compiled [`CleanOptions::only`] patterns
+    only: Vec<OnlyPattern>,
+    observer: Tally<O>,
+    /// Identities of the directories scanned, to scan each only once even if reachable through
+    /// several paths, e.g. symbolic links
+    scanned_dirs: Mutex<HashSet<FileId>>,
+    /// Identities of the target directories queued for removal, to remove each only once
+    queued_targets: Mutex<HashSet<FileId>>,
+    /// Target directories found while removals are deferred
+    candidates: Mutex<Vec<Candidate>>,
+    /// Number of roots that could not be read
+    unreadable_roots: AtomicUsize,
+    /// Number of errors while scanning
+    scan_errors: AtomicUsize,
+    /// Number of target directories whose access time was checked
+    atime_checks: AtomicUsize,
+    /// Number of target directories whose access time matched their modification time
+    stale_atimes: AtomicUsize,
+    /// Number of target directories dropped because cleaning stopped early
+    unprocessed: AtomicUsize,
+    /// Whether enough space was found available to stop removing
+    free_space_reached: AtomicBool,
+    /// Whether available space could not be determined, to warn once
+    free_space_unknown: AtomicBool,
+    scan_times: PoolTimes,
+    remove_times: PoolTimes,
+    /// Slowest directories to read if requested, the fastest of them on top
+    slowest_dirs: Mutex<BinaryHeap<Reverse<DirTiming>>>,
+}
+
+/// Number of access times to check before warning that they seem stale
+const STALE_ATIME_MIN_CHECKS: usize = 5;
+
+impl<O: Observer + Sync> Cleaner<'_, O> {
+    /// Scans `roots` and performs `removals` along with the removals found, until all jobs are
+    /// done
+    ///
+    /// Scanning and removing happen in separate pools of workers. If `defer_removals` is true,
+    /// the target directories to remove are collected in `candidates` instead.
+    fn run(&self, roots: &[&Path], removals: Vec<Removal>, defer_removals: bool) {
+        let (scan_sender, scan_receiver) = self.channel();
+        let (removal_sender, removal_receiver) = self.channel();
+        thread::scope(|scope| {
+            let scanners = std::iter::repeat_with(|| {
+                scope.spawn(|| {
+                    self.scan_times.track(&scan_receiver, |job: ScanJob| {
+                        // Dropping jobs once stopped drains the channel until the workers exit.
+                        if !self.is_stopped() {
+                            let dir = job.dir.clone();
+                            self.guarded(&dir, || self.scan(job));
+                        }
+                    })
+                })
+            })
+            .take(self.options.scan_worker_count.max(1))
+            .collect::<Vec<_>>();
+            let removers = std::iter::repeat_with(|| {
+                scope.spawn(|| {
+                    self.remove_times.track(&removal_receiver, |removal| {
+                        if self.is_stopped() {
+                            self.unprocessed.fetch_add(1, atomic::Ordering::SeqCst);
+                        } else if defer_removals {
+                            self.defer_removal(removal);
+                        } else {
+                            self.guarded(&removal.target, || self.remove(&removal));
+                        }
+                    })
+                })
+            })
+            .take(self.options.remove_worker_count.max(1))
+            .collect::<Vec<_>>();
+            // Jobs are queued once workers run, as bounded channels could otherwise fill up. The
+            // receivers outlive the workers, so sending cannot fail.
+            for root in roots {
+                let _ = scan_sender.send(ScanJob {
+                    dir: root.to_path_buf(),
+                    id: None,
+                    depth: 0,
+                    ignores: None,
+                    workspace: None,
+                    in_git_repo: false,
+                    scans: scan_sender.clone(),
+                    removals: removal_sender.clone(),
+                });
+            }
+            for removal in removals {
+                let _ = removal_sender.send(removal);
+            }
+            // Each pending scan job holds senders, so the channels close once scanning is done.
+            drop(scan_sender);
+            drop(removal_sender);
+            for worker in scanners.into_iter().chain(removers) {
+                // Workers only panic if the observer does when reporting a panic.
+                if worker.join().is_err() {
+                    self.observer.errors.fetch_add(1, atomic::Ordering::SeqCst);
+                }
+            }
+        });
+    }
+}
+
+impl<O: Observer> Cleaner<'_, O> {
+    fn channel<T>(&self) -> (Sender<T>, Receiver<T>) {
+        match self.options.queue_bound {
+            Some(bound) => crossbeam::channel::bounded(bound),
+            None => crossbeam::channel::unbounded(),
+        }
+    }
+
+    /// Runs the job `f` handling `path`, reporting a panic as an error so that workers keep
+    /// processing other jobs
+    fn guarded(&self, path: &Path, f: impl FnOnce()) {
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            self.observer
+                .on_error(path, io::Error::other(format!("Panicked: {message}")));
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.is_interrupted() || self.is_timed_out() || self.has_failed()
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.options
+            .stop
+            .as_ref()
+            .is_some_and(|stop| stop.load(atomic::Ordering::SeqCst))
+    }
+
+    /// Returns whether cleaning stops because of an error
+    fn has_failed(&self) -> bool {
+        self.options.fail_fast && self.observer.errors.load(atomic::Ordering::SeqCst) > 0
+    }
+
+    fn is_timed_out(&self) -> bool {
+        self.options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(dir = %job.dir.display()))
+    )]
+    fn scan(&self, mut job: ScanJob) {
+        if !self.start_scan(&job.dir, job.id.take()) {
+            return;
+        }
+        let dir = job.dir.clone();
+        let is_root = job.depth == 0;
+        let scans = job.scans.clone();
+        match self.entries(job) {
+            Ok(jobs) => {
+                for job in jobs {
+                    match job {
+                        Ok(job) => self.queue_scan(&scans, job),
+                        Err(e) => self.on_scan_error(&dir, e),
+                    }
+                }
+            }
+            Err(e) => {
+                if is_root {
+                    self.unreadable_roots.fetch_add(1, atomic::Ordering::SeqCst);
+                }
+                self.on_scan_error(&dir, e)
+            }
+        }
+        self.observer.on_scanned(&dir);
+    }
+
+    fn on_scan_error(&self, dir: &Path, e: io::Error) {
+        self.scan_errors.fetch_add(1, atomic::Ordering::SeqCst);
+        self.observer.on_scan_error(dir, e)
+    }
+
+    /// Queues `job` or scans it right away, depending on the scan order and queue bound
+    fn queue_scan(&self, scans: &Sender<ScanJob>, job: ScanJob) {
+        // Depth first, jobs are only queued for idle workers to pick up.
+        let job = if self.options.order == ScanOrder::DepthFirst && !scans.is_empty() {
+            job
+        } else {
+            // Scanning in place when the queue is full cannot deadlock, unlike waiting for other
+            // scanners that may themselves be waiting. The receiver outlives the workers, so
+            // sending cannot fail otherwise.
+            match scans.try_send(job) {
+                Err(TrySendError::Full(job)) => job,
+                _ => return,
+            }
+        };
+        if !self.is_stopped() {
+            self.scan(job);
+        }
+    }
+
+    /// Queues the removal of the build artifact directories in the directory of `job` if it holds
+    /// a manifest, returning the jobs scanning its other subdirectories
+    ///
+    /// Whether the directory is a package is known before reading any entry, so entries are
+    /// handled one at a time as they are read, whatever their order. Entries are never
+    /// collected, so memory use does not grow with the number of files in a directory, only
+    /// with the subdirectories waiting to be scanned, which [`CleanOptions::queue_bound`]
+    /// bounds.
+    fn entries<'a>(
+        &'a self,
+        job: ScanJob,
+    ) -> Result<impl Iterator<Item = Result<ScanJob, io::Error>> + 'a, io::Error> {
+        let job = ScanJob {
+            ignores: self.ignores_in(&job.dir, job.ignores),
+            workspace: self.workspace_in(&job.dir, job.workspace),
+            in_git_repo: job.in_git_repo || self.options.git_only && is_git_repo(&job.dir),
+            ..job
+        };
+        let entries = self.timed_entries(&job.dir)?;
+        // Checking for the manifest up front keeps the outcome independent of the order of
+        // entries.
+        let is_workspace_root = job
+            .workspace
+            .as_ref()
+            .is_some_and(|workspace| workspace.root == job.dir);
+        let has_manifest = job.dir.join(&self.options.manifest_name).is_file();
+        let is_excluded_project = has_manifest
+            && self
+                .options
+                .exclude_projects
+                .is_match(self.relative(&job.dir));
+        let is_package = has_manifest
+            && !is_excluded_project
+            && !(self.options.keep_workspace_root && is_workspace_root);
+        let descend = !is_excluded_project;
+        let (shared_target_error, marked_target) = if is_package {
+            (
+                self.queue_shared_target(&job).err(),
+                self.queue_marked_target(&job),
+            )
+        } else {
+            (None, Ok(None))
+        };
+        let (marked_target, marker_error) = match marked_target {
+            Ok(marked_target) => (marked_target, None),
+            Err(e) => (None, Some(e)),
+        };
+        Ok(shared_target_error
+            .into_iter()
+            .chain(marker_error)
+            .map(Err)
+            .chain(entries.filter_map(move |entry| {
+                let context = EntryContext {
+                    is_package,
+                    descend,
+                    marked_target: marked_target.as_deref(),
+                };
+                entry
+                    .and_then(|entry| self.process_entry(entry, &job, context))
+                    .transpose()
+            })))
+    }
+
+    /// Returns the entries of `dir`, recording the time taken to read them if the options ask for
+    /// the slowest directories
+    fn timed_entries<'a>(
+        &'a self,
+        dir: &Path,
+    ) -> Result<impl Iterator<Item = Result<DirEntry, io::Error>> + 'a, io::Error> {
+        let mut timing = self.options.slowest_dirs.map(|_| DirTiming {
+            duration: Duration::ZERO,
+            entries: 0,
+            path: dir.to_owned(),
+        });
+        let start = Instant::now();
+        let mut entries = read_dir(dir)?;
+        if let Some(timing) = &mut timing {
+            timing.duration = start.elapsed();
+        }
+        Ok(std::iter::from_fn(move || {
+            let Some(current) = &mut timing else {
+                return entries.next();
+            };
+            let start = Instant::now();
+            let entry = entries.next();
+            current.duration += start.elapsed();
+            if entry.is_some() {
+                current.entries += 1;
+            } else if let Some(timing) = timing.take() {
+                self.record_dir_timing(timing);
+            }
+            entry
+        }))
+    }
+
+    /// Records `timing` if it is among the slowest directories to list
+    fn record_dir_timing(&self, timing: DirTiming) {
+        let Some(max) = self.options.slowest_dirs else {
+            return;
+        };
+        let mut slowest = self.slowest_dirs.lock().unwrap();
+        slowest.push(Reverse(timing));
+        if slowest.len() > max {
+            slowest.pop();
+        }
+    }
+
+    /// Returns the ignore rules applying to the entries of `dir`
+    fn ignores_in(&self, dir: &Path, parent: Option<Arc<IgnoreStack>>) -> Option<Arc<IgnoreStack>> {
+        if !self.options.respect_gitignore {
+            return None;
+        }
+        let path = dir.join(".gitignore");
+        if !path.is_file() {
+            return parent;
+        }
+        let (matcher, error) = Gitignore::new(&path);
+        if let Some(e) = error {
+            self.observer.on_warning(&path, &e.to_string());
+        }
+        Some(Arc::new(IgnoreStack { matcher, parent }))
+    }
+
+    /// Returns the workspace whose members may be found among the entries of `dir`
+    fn workspace_in(&self, dir: &Path, parent: Option<Arc<Workspace>>) -> Option<Arc<Workspace>> {
+        let path = dir.join(&self.options.manifest_name);
+        let Ok(manifest) = read_to_string(&path) else {
+            return parent;
+        };
+        match Workspace::from_manifest(dir, &manifest) {
+            Ok(Some(workspace)) => Some(Arc::new(workspace)),
+            Ok(None) => parent,
+            Err(e) => {
+                self.observer.on_warning(&path, &e);
+                parent
+            }
+        }
+    }
+
+    fn process_entry(
+        &self,
+        entry: DirEntry,
+        job: &ScanJob,
+        context: EntryContext<'_>,
+    ) -> Result<Option<ScanJob>, io::Error> {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let is_link = file_type.is_symlink();
+        let link_metadata = if is_link && self.options.follow_symlinks {
+            metadata(&path).ok()
+        } else {
+            None
+        };
+        let is_dir = match &link_metadata {
+            Some(metadata) => metadata.is_dir(),
+            None => file_type.is_dir(),
+        };
+        Ok(match path.file_name() {
+            Some(name) => {
+                if is_dir && context.is_package && self.is_artifact_dir(name) {
+                    let is_target = name == "target";
+                    let path = if is_link { canonicalize(&path)? } else { path };
+                    let path = if is_target {
+                        match self.profile_dir(path) {
+                            Some(path) => path,
+                            None => return Ok(None),
+                        }
+                    } else {
+                        path
+                    };
+                    self.queue_removal(job, path, is_target)?;
+                    None
+                } else if is_dir
+                    && name != "target"
+                    && context.descend
+                    && context.marked_target != Some(path.as_path())
+                    && (self.options.include_hidden || !name.as_encoded_bytes().starts_with(b"."))
+                    && self.options.max_depth.is_none_or(|max| job.depth < max)
+                    && !self.is_excluded(&path)
+                    && self.may_hold_only(&path)
+                    && !job
+                        .ignores
+                        .as_ref()
+                        .is_some_and(|ignores| ignores.is_ignored(&path))
+                    // The target directory of a workspace root holds the build artifacts of its
+                    // members.
+                    && (self.options.keep_workspace_root
+                        || self.options.nested
+                        || !job
+                            .workspace
+                            .as_ref()
+                            .is_some_and(|workspace| workspace.is_member(&path)))
+                {
+                    let metadata = match link_metadata {
+                        Some(metadata) => Ok(metadata),
+                        None => entry.metadata(),
+                    };
+                    let id = metadata.and_then(|metadata| file_id(&path, &metadata)).ok();
+                    Some(job.child(path, id))
+                } else {
+                    None
+                }
+            }
+            None => None,
+        })
+    }
+
+    /// Returns whether a directory named `name` next to a manifest holds build artifacts
+    fn is_artifact_dir(&self, name: &OsStr) -> bool {
+        name == "target"
+            || self
+                .options
+                .extra_dirs
+                .iter()
+                .any(|extra| name == extra.as_str())
+    }
+
+    /// Records `dir` with identity `id`, if known, as scanned, returning whether it was not
+    /// already
+    ///
+    /// A directory whose identity cannot be determined is considered new.
+    fn start_scan(&self, dir: &Path, id: Option<FileId>) -> bool {
+        let id = match id {
+            Some(id) => Ok(id),
+            None => metadata(dir).and_then(|metadata| file_id(dir, &metadata)),
+        };
+        match id {
+            Ok(id) => self.scanned_dirs.lock().unwrap().insert(id),
+            Err(_) => true,
+        }
+    }
+
+    /// Returns whether `path` matches one of the excluded patterns
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.options.exclude.is_match(self.relative(path))
+    }
+
+    /// Returns whether `target` matches one of the patterns to only clean, if any
+    fn is_only(&self, target: &Path) -> bool {
+        let target = self.relative(target);
+        self.only.is_empty() || self.only.iter().any(|only| only.matcher.is_match(target))
+    }
+
+    /// Returns whether `dir` may hold paths matching one of the patterns to only clean, if any
+    fn may_hold_only(&self, dir: &Path) -> bool {
+        let dir = self.relative(dir);
+        self.only.is_empty()
+            || self
+                .only
+                .iter()
+                .any(|only| only.prefix.starts_with(dir) || dir.starts_with(&only.prefix))
+    }
+
+    /// Returns `path` relative to the root it is in, if any
+    fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path)
+    }
+
+    /// Queues the removal of the shared target directory, if any, for the package in `job`
+    fn queue_shared_target(&self, job: &ScanJob) -> Result<(), io::Error> {
+        let Some(target) = &self.options.target_dir else {
+            return Ok(());
+        };
+        if !target.is_dir() {
+            return Ok(());
+        }
+        match self.profile_dir(target.clone()) {
+            Some(target) => self.queue_removal(job, target, false),
+            None => Ok(()),
+        }
+    }
+
+    /// Queues the removal of the directory named by the target marker file, if any, of the
+    /// package in `job`, returning the directory
+    fn queue_marked_target(&self, job: &ScanJob) -> Result<Option<PathBuf>, io::Error> {
+        let Some(marker) = &self.options.target_marker else {
+            return Ok(None);
+        };
+        let marker = job.dir.join(marker);
+        let contents = match read_to_string(&marker) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let target = job.dir.join(contents.trim());
+        if !target.is_dir() {
+            return Ok(None);
+        }
+        // Removing the package along with its build artifacts would be disastrous.
+        if canonicalize(&job.dir)?.starts_with(canonicalize(&target)?) {
+            self.observer.on_warning(
+                &marker,
+                "Target marker names the package directory or a parent, ignoring it",
+            );
+            return Ok(None);
+        }
+        let Some(target) = self.profile_dir(target.clone()) else {
+            return Ok(Some(target));
+        };
+        self.queue_removal(job, target.clone(), false)?;
+        Ok(Some(target))
+    }
+
+    /// Returns the directory to remove in `target` for the selected profile, if it exists
+    fn profile_dir(&self, target: PathBuf) -> Option<PathBuf> {
+        match &self.options.profile {
+            Some(profile) => Some(target.join(profile)).filter(|dir| dir.is_dir()),
+            None => Some(target),
+        }
+    }
+
+    /// Queues the removal of `target` of the package in `job` unless the options exclude it or
+    /// its removal is already queued, `is_target` telling whether it is the `target` directory
+    /// next to the manifest
+    fn queue_removal(
+        &self,
+        job: &ScanJob,
+        target: PathBuf,
+        is_target: bool,
+    ) -> Result<(), io::Error> {
+        if self.options.git_only && !job.in_git_repo {
+            return Ok(());
+        }
+        if let Some(removal) = self.removal(&job.dir, target, is_target)? {
+            // The receiver outlives the workers, so sending cannot fail.
+            let _ = job.removals.send(removal);
+        }
+        Ok(())
+    }
+
+    /// Returns the job removing `target` of the package in `package_dir` unless the options
+    /// exclude it or its removal is already queued
+    fn removal(
+        &self,
+        package_dir: &Path,
+        target: PathBuf,
+        is_target: bool,
+    ) -> Result<Option<Removal>, io::Error> {
+        if self.is_excluded(&target)
+            || !self.is_only(&target)
+            || !self.is_selected_package(package_dir)
+        {
+            return Ok(None);
+        }
+        if self.options.older_than.is_some() || self.options.accessed_before.is_some() {
+            let metadata = metadata(&target)?;
+            let modified = metadata.modified();
+            if self.options.accessed_before.is_some() {
+                self.check_access_time(&metadata);
+            }
+            if self.is_recent(&target, modified, self.options.older_than, "modification")
+                || self.is_recent(
+                    &target,
+                    metadata.accessed(),
+                    self.options.accessed_before,
+                    "access",
+                )
+            {
+                return Ok(None);
+            }
+        }
+        if self.options.exclude_newer_than_manifest
+            && self.is_newer_than_manifest(package_dir, &target)
+        {
+            return Ok(None);
+        }
+        if !self.is_unchanged(package_dir) {
+            return Ok(None);
+        }
+        if self.options.keep_pinned && is_pinned(package_dir) {
+            self.observer.on_skipped(&target, SkipReason::Pinned);
+            return Ok(None);
+        }
+        if !self.options.ignore_locks && self.preserving_access_time(&target, || is_locked(&target))
+        {
+            self.observer.on_skipped(&target, SkipReason::Locked);
+            return Ok(None);
+        }
+        let id = metadata(&target).and_then(|metadata| file_id(&target, &metadata))?;
+        if !self.queued_targets.lock().unwrap().insert(id) {
+            return Ok(None);
+        }
+        if self.options.skip_empty
+            && self
+                .preserving_access_time(&target, || read_dir(&target))?
+                .next()
+                .is_none()
+        {
+            self.observer.on_skipped(&target, SkipReason::Empty);
+            return Ok(None);
+        }
+        Ok(Some(Removal {
+            target,
+            package_dir: package_dir.to_owned(),
+            is_target,
+            size: None,
+        }))
+    }
+
+    /// Returns whether the options select the package in `package_dir`
+    fn is_selected_package(&self, package_dir: &Path) -> bool {
+        if self.options.packages.is_empty() {
+            return true;
+        }
+        let path = package_dir.join(&self.options.manifest_name);
+        let Ok(manifest) = read_to_string(&path) else {
+            return false;
+        };
+        match package_name(&manifest) {
+            Ok(name) => name.is_some_and(|name| self.options.packages.contains(&name)),
+            Err(e) => {
+                self.observer.on_warning(&path, &e);
+                false
+            }
+        }
+    }
+
+    /// Returns whether `target` was modified after the lock file of the package in
+    /// `package_dir`, or its manifest if there is no lock file
+    ///
+    /// Times that cannot be determined are not newer.
+    fn is_newer_than_manifest(&self, package_dir: &Path, target: &Path) -> bool {
+        let modified = |path: &Path| metadata(path).and_then(|metadata| metadata.modified());
+        let manifest = modified(&package_dir.join("Cargo.lock"))
+            .or_else(|_| modified(&package_dir.join(&self.options.manifest_name)));
+        match (modified(target), manifest) {
+            (Ok(target), Ok(manifest)) => target > manifest,
+            _ => false,
+        }
+    }
+
+    /// Returns whether git reports no changes in `package_dir` since the revision given in the
+    /// options, if any
+    ///
+    /// Packages outside a git repository are considered changed.
+    fn is_unchanged(&self, package_dir: &Path) -> bool {
+        let Some(rev) = &self.options.unchanged_since else {
+            return true;
+        };
+        let output = Command::new("git")
+            .args(["diff", "--quiet", rev, "--", "."])
+            .current_dir(package_dir)
+            .output();
+        match output {
+            Ok(output) if output.status.success() => true,
+            Ok(output) if output.status.code() == Some(1) => false,
+            Ok(output) => {
+                self.observer.on_warning(
+                    package_dir,
+                    &format!(
+                        "git diff failed ({}), considering the package changed: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim(),
+                    ),
+                );
+                false
+            }
+            Err(e) => {
+                self.observer.on_warning(
+                    package_dir,
+                    &format!("Failed to run git, considering the package changed: {e}"),
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns whether `time` is less than `min_age` ago, `kind` naming the time in warnings
+    ///
+    /// A time that cannot be determined is not recent.
+    fn is_recent(
+        &self,
+        target: &Path,
+        time: Result<SystemTime, io::Error>,
+        min_age: Option<Duration>,
+        kind: &str,
+    ) -> bool {
+        let Some(min_age) = min_age else {
+            return false;
+        };
+        match time {
+            // A time in the future counts as just now.
+            Ok(time) => SystemTime::now().duration_since(time).unwrap_or_default() < min_age,
+            Err(e) => {
+                self.observer.on_warning(
+                    target,
+                    &format!("Cannot get {kind} time, removing regardless of age: {e}"),
+                );
+                false
+            }
+        }
+    }
+
+    /// Counts target directories whose access time matches their modification time, a sign
+    /// that the file system does not record access times
+    fn check_access_time(&self, metadata: &Metadata) {
+        self.atime_checks.fetch_add(1, atomic::Ordering::SeqCst);
+        if let (Ok(accessed), Ok(modified)) = (metadata.accessed(), metadata.modified()) {
+            if accessed == modified {
+                self.stale_atimes.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Runs `f`, which reads `target`, restoring the access time of `target` afterwards if
+    /// access times matter, so that reading does not make it look in use
+    fn preserving_access_time<T>(&self, target: &Path, f: impl FnOnce() -> T) -> T {
+        if self.options.accessed_before.is_none() && self.options.reclaim.is_none() {
+            return f();
+        }
+        let accessed = metadata(target).and_then(|metadata| metadata.accessed());
+        let result = f();
+        if let Ok(accessed) = accessed {
+            // Failing to restore the access time only affects later runs.
+            let _ = File::open(target)
+                .and_then(|dir| dir.set_times(FileTimes::new().set_accessed(accessed)));
+        }
+        result
+    }
+
+    /// Warns if most access times checked match modification times
+    fn warn_stale_atimes(&self) {
+        let checks = self.atime_checks.load(atomic::Ordering::SeqCst);
+        let stale = self.stale_atimes.load(atomic::Ordering::SeqCst);
+        if checks >= STALE_ATIME_MIN_CHECKS && stale * 2 > checks {
+            self.observer.on_warning(
+                self.roots.first().copied().unwrap_or(Path::new(".")),
+                &format!(
+                    "{stale} of {checks} target directories were last accessed when modified, \
+                    access times may not be recorded (e.g. with noatime)"
+                ),
+            );
+        }
+    }
+
+    /// Returns what the tree at `root` holds, measured according to the options
+    fn dir_size(&self, root: &Path) -> Option<DirSize> {
+        tree_size(root, self.options.size_mode, |_| false)
+    }
+
+    /// Records `removal` for later, along with what is needed to rank it
+    fn defer_removal(&self, mut removal: Removal) {
+        let metadata = metadata(&removal.target).ok();
+        let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+        let mut accessed = None;
+        if self.options.reclaim.is_some() || self.options.biggest_first {
+            accessed = metadata.as_ref().and_then(|m| m.accessed().ok());
+            removal.size =
+                self.preserving_access_time(&removal.target, || self.dir_size(&removal.target));
+        }
+        self.candidates.lock().unwrap().push(Candidate {
+            removal,
+            modified,
+            accessed,
+        });
+    }
+
+    /// Returns the removals to perform among `candidates`, reporting the others as skipped
+    fn select(&self, mut candidates: Vec<Candidate>) -> Vec<Removal> {
+        if let Some(keep_recent) = self.options.keep_recent {
+            // Targets whose modification time is unknown sort last, as if oldest.
+            candidates.sort_by_key(|candidate| Reverse(candidate.modified));
+            let kept = keep_recent.min(candidates.len());
+            for candidate in candidates.drain(..kept) {
+                self.observer
+                    .on_skipped(&candidate.removal.target, SkipReason::Recent);
+            }
+        }
+        if self.options.biggest_first {
+            // Directories of unknown size sort last, as if empty.
+            candidates
+                .sort_by_key(|candidate| Reverse(candidate.removal.size.map(|size| size.bytes)));
+        }
+        let Some(goal) = self.options.reclaim else {
+            return candidates.into_iter().map(|c| c.removal).collect();
+        };
+        if !self.options.biggest_first {
+            let now = SystemTime::now();
+            candidates.sort_by(|a, b| b.staleness(now).total_cmp(&a.staleness(now)));
+        }
+        let mut reclaimed = 0;
+        let mut removals = Vec::new();
+        for candidate in candidates {
+            let removal = candidate.removal;
+            if reclaimed >= goal {
+                self.observer
+                    .on_skipped(&removal.target, SkipReason::GoalReached);
+                continue;
+            }
+            reclaimed += removal.size.map_or(0, |size| size.bytes);
+            removals.push(removal);
+        }
+        removals
+    }
+
+    /// Removes the target directory of `removal` unless declined by the observer
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(target = %removal.target.display()))
+    )]
+    fn remove(&self, removal: &Removal) {
+        let target = &removal.target;
+        let package_dir = &removal.package_dir;
+        if self.has_enough_free_space(target) {
+            self.observer.on_skipped(target, SkipReason::GoalReached);
+            return;
+        }
+        if !self.observer.should_remove(target, package_dir) {
+            self.observer.on_skipped(target, SkipReason::Declined);
+            return;
+        }
+        let size = match removal.size {
+            Some(size) => Some(size),
+            None if self.options.measure
+                || self.options.min_size.is_some()
+                || self.options.confirm_over.is_some()
+                || self.options.target_ratio.is_some() =>
+            {
+                self.preserving_access_time(target, || self.dir_size(target))
+            }
+            None => None,
+        };
+        if let (Some(min_size), Some(size)) = (self.options.min_size, size) {
+            if size.bytes < min_size {
+                self.observer.on_skipped(target, SkipReason::TooSmall);
+                return;
+            }
+        }
+        if let (Some(ratio), Some(size)) = (self.options.target_ratio, size) {
+            let mode = self.options.size_mode;
+            if let Some(rest) = tree_size(package_dir, mode, |dir| dir == target.as_path()) {
+                let total = size.bytes + rest.bytes;
+                if total > 0 && (size.bytes as f64) < ratio * total as f64 {
+                    self.observer.on_skipped(target, SkipReason::BelowRatio);
+                    return;
+                }
+            }
+        }
+        // Directories of unknown size are confirmed in case they are large.
+        let needs_confirmation = match (self.options.confirm_over, size) {
+            (Some(threshold), Some(size)) => size.bytes > threshold,
+            _ => true,
+        };
+        if needs_confirmation && !self.observer.confirm_removal(target) {
+            self.observer.on_skipped(target, SkipReason::Declined);
+            return;
+        }
+        if let Some(size) = size {
+            self.observer.on_measured(target, size);
+        }
+        let bytes = size.map(|size| size.bytes);
+        if self.options.dry_run {
+            self.observer.record_contents(size);
+            self.observer.on_would_remove(target, package_dir, bytes);
+            return;
+        }
+        // The size measured up front is not measured again between attempts, as it counts what
+        // ends up freed once an attempt succeeds. Failed attempts may remove part of the directory
+        // though, so what is left is measured if all attempts fail.
+        let mut partially_removed = false;
+        let result = self.retrying(|| {
+            // Other build artifact directories are unknown to cargo.
+            let result = if self.options.cargo_clean && removal.is_target {
+                self.cargo_clean(target, package_dir)
+            } else {
+                self.delete(target).or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound if self.options.ignore_missing => Ok(()),
+                    _ => Err(e),
+                })
+            }
+            .and_then(|()| self.verify_removed(target));
+            partially_removed |= result
+                .as_ref()
+                .is_err_and(|e| e.kind() != io::ErrorKind::NotFound);
+            result
+        });
+        match result {
+            Ok(()) => {
+                self.observer.record_contents(size);
+                self.observer.on_removal(target, package_dir, bytes)
+            }
+            Err(e) => {
+                if let (true, Some(size)) = (partially_removed, size) {
+                    if let Some(left) = self.dir_size(target) {
+                        self.observer.record_partial_removal(DirSize {
+                            bytes: size.bytes.saturating_sub(left.bytes),
+                            files: size.files.saturating_sub(left.files),
+                            dirs: size.dirs.saturating_sub(left.dirs),
+                        });
+                    }
+                }
+                self.observer.on_error(target, e)
+            }
+        }
+    }
+
+    /// Returns whether the file system holding `target` has the space available that the
+    /// options ask for, if any
+    fn has_enough_free_space(&self, target: &Path) -> bool {
+        let Some(goal) = self.options.min_free_space else {
+            return false;
+        };
+        if self.free_space_unknown.load(atomic::Ordering::SeqCst) {
+            return false;
+        }
+        match fs4::available_space(target) {
+            Ok(available) if available >= goal => {
+                self.free_space_reached
+                    .store(true, atomic::Ordering::SeqCst);
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                if !self.free_space_unknown.swap(true, atomic::Ordering::SeqCst) {
+                    self.observer.on_warning(
+                        target,
+                        &format!("Cannot get available space, removing regardless: {e}"),
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// Returns an error if the options ask to verify removals and `target` still exists
+    fn verify_removed(&self, target: &Path) -> Result<(), io::Error> {
+        if self.options.verify && target.symlink_metadata().is_ok() {
+            Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "Directory still exists after removal",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Deletes `target`, or moves it to the trash if the options say so
+    fn delete(&self, target: &Path) -> Result<(), io::Error> {
+        #[cfg(feature = "trash")]
+        if self.options.trash {
+            match trash::delete(target) {
+                Ok(()) => return Ok(()),
+                Err(e) if self.options.strict_trash => {
+                    return Err(io::Error::other(format!(
+                        "Failed to move to the trash: {e}"
+                    )))
+                }
+                Err(e) => self.observer.on_warning(
+                    target,
+                    &format!("Failed to move to the trash, deleting instead: {e}"),
+                ),
+            }
+        }
+        self.delete_tree(target)
+    }
+
+    /// Deletes `target` and everything in it, through the remover of the options if any
+    fn delete_tree(&self, target: &Path) -> Result<(), io::Error> {
+        #[cfg(feature = "testing")]
+        return (self.options.remover)(target);
+        #[cfg(not(feature = "testing"))]
+        remove_tree(target)
+    }
+
+    /// Runs `f` until it succeeds, fails with a permanent error, or retries are exhausted
+    fn retrying<F>(&self, mut f: F) -> Result<(), io::Error>
+    where
+        F: FnMut() -> Result<(), io::Error>,
+    {
+        let mut delay = Duration::from_millis(100);
+        let mut retries_left = self.options.retries;
+        loop {
+            match f() {
+                Err(e) if retries_left > 0 && is_transient(&e) => {
+                    thread::sleep(delay);
+                    delay *= 2;
+                    retries_left -= 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs `cargo clean` in `package_dir`, for the selected profile if any, falling back to
+    /// removing `target` if `cargo` is not found
+    fn cargo_clean(&self, target: &Path, package_dir: &Path) -> Result<(), io::Error> {
+        let mut command = Command::new("cargo");
+        command.arg("clean").current_dir(package_dir);
+        if let Some(profile) = &self.options.profile {
+            // The debug directory holds the build artifacts of the dev profile.
+            let profile = if profile == "debug" { "dev" } else { profile };
+            command.args(["--profile", profile]);
+        }
+        match command.output() {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(io::Error::other(format!(
+                "cargo clean failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.observer.on_warning(
+                    package_dir,
+                    "cargo not found, removing the target directory instead",
+                );
+                self.delete_tree(target)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Receives notifications of what happens while cleaning
+///
+/// Methods are called concurrently from the worker threads, which is why [`clean`] requires
+/// observers to be `Sync`. They should return quickly as they hold up the calling worker.
+///
+/// [`FnObserver`] implements this trait with closures for simple cases.
+pub trait Observer {
+    /// Called when an error occurs while scanning or removing `path`
+    fn on_error(&self, path: &Path, e: io::Error);
+    /// Called after removing `path`, the build artifact directory of the package in
+    /// `package_dir`, `size` being the number of bytes freed, if known
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>);
+    /// Called after scanning `dir`
+    fn on_scanned(&self, dir: &Path);
+    /// Called instead of removing `path`, the build artifact directory of the package in
+    /// `package_dir`, in a dry run, `size` being the number of bytes that would be freed, if
+    /// known
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>);
+    /// Called when something unexpected happens that does not prevent cleaning
+    fn on_warning(&self, path: &Path, message: &str);
+    /// Called when the build artifact directory at `path` is left in place
+    fn on_skipped(&self, path: &Path, reason: SkipReason);
+    /// Called to confirm removing `path` unless [`CleanOptions::confirm_over`] says it is small
+    /// enough, `path` being skipped if this returns `false`
+    ///
+    /// This is meant to prompt users, leaving policies to `should_remove`.
+    fn confirm_removal(&self, path: &Path) -> bool {
+        let _ = path;
+        true
+    }
+    /// Called with the contents of `path` right before removing it, if measured
+    fn on_measured(&self, path: &Path, size: DirSize) {
+        let _ = (path, size);
+    }
+    /// Called instead of `on_error` when the error occurs while scanning `path` rather than
+    /// removing it
+    fn on_scan_error(&self, path: &Path, e: io::Error) {
+        self.on_error(path, e)
+    }
+    /// Called before measuring and removing `path`, the build artifact directory of the package
+    /// in `package_dir`, which is skipped if this returns `false`
+    ///
+    /// Unlike `confirm_removal`, this is called for every directory about to be removed, even in
+    /// a dry run or when [`CleanOptions::confirm_over`] is set, to enforce a policy.
+    ///
+    /// # Example
+    /// ```
+    /// use cleanrust::{clean, CleanOptions, Observer, SkipReason};
+    /// use std::{fs, io, path::Path};
+    ///
+    /// /// Policy leaving in place build artifacts in a `keep` directory
+    /// struct KeepPolicy;
+    ///
+    /// impl Observer for KeepPolicy {
+    ///     fn on_error(&self, _: &Path, _: io::Error) {}
+    ///     fn on_removal(&self, _: &Path, _: &Path, _: Option<u64>) {}
+    ///     fn on_scanned(&self, _: &Path) {}
+    ///     fn on_would_remove(&self, _: &Path, _: &Path, _: Option<u64>) {}
+    ///     fn on_warning(&self, _: &Path, _: &str) {}
+    ///     fn on_skipped(&self, _: &Path, _: SkipReason) {}
+    ///     fn should_remove(&self, path: &Path, _: &Path) -> bool {
+    ///         !path.components().any(|component| component.as_os_str() == "keep")
+    ///     }
+    /// }
+    ///
+    /// let dir = std::env::temp_dir().join("cleanrust-should-remove");
+    /// for package in ["keep/a", "b"] {
+    ///     fs::create_dir_all(dir.join(package).join("target"))?;
+    ///     fs::write(dir.join(package).join("Cargo.toml"), "")?;
+    /// }
+    /// let summary = clean(&[&dir], &CleanOptions::default(), KeepPolicy);
+    /// assert_eq!((summary.removed, summary.skipped), (1, 1));
+    /// assert!(dir.join("keep/a/target").exists());
+    /// fs::remove_dir_all(&dir)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    fn should_remove(&self, path: &Path, package_dir: &Path) -> bool {
+        let _ = (path, package_dir);
+        true
+    }
+}
+
+/// Reason for leaving a build artifact directory in place
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The observer declined or disallowed the removal
+    Declined,
+    /// A build holds a lock in the directory
+    Locked,
+    /// The directory is smaller than the minimum size
+    TooSmall,
+    /// The directory is among the most recently modified ones to keep
+    Recent,
+    /// Enough space is freed by removing other directories, or available
+    GoalReached,
+    /// The directory holds nothing
+    Empty,
+    /// The directory makes up too small a share of its package
+    BelowRatio,
+    /// The package is pinned to a toolchain
+    Pinned,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Declined => f.write_str("declined"),
+            SkipReason::Locked => f.write_str("locked"),
+            SkipReason::TooSmall => f.write_str("too small"),
+            SkipReason::Recent => f.write_str("recent"),
+            SkipReason::GoalReached => f.write_str("goal reached"),
+            SkipReason::Empty => f.write_str("empty"),
+            SkipReason::BelowRatio => f.write_str("below target ratio"),
+            SkipReason::Pinned => f.write_str("pinned"),
+        }
+    }
+}
+
+impl<T: Observer + ?Sized> Observer for &T {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        (**self).on_error(path, e)
+    }
+
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        (**self).on_removal(path, package_dir, size)
+    }
+
+    fn on_scanned(&self, dir: &Path) {
+        (**self).on_scanned(dir)
+    }
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        (**self).on_would_remove(path, package_dir, size)
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        (**self).on_warning(path, message)
+    }
+
+    fn on_skipped(&self, path: &Path, reason: SkipReason) {
+        (**self).on_skipped(path, reason)
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        (**self).confirm_removal(path)
+    }
+
+    fn on_measured(&self, path: &Path, size: DirSize) {
+        (**self).on_measured(path, size)
+    }
+
+    fn on_scan_error(&self, path: &Path, e: io::Error) {
+        (**self).on_scan_error(path, e)
+    }
+
+    fn should_remove(&self, path: &Path, package_dir: &Path) -> bool {
+        (**self).should_remove(path, package_dir)
+    }
+}
+
+/// Observer calling closures on errors, removals and scanned directories
+///
+/// Events without a closure are ignored, and all removals are accepted.
+///
+/// # Example
+/// ```
+/// use cleanrust::{clean, CleanOptions, FnObserver};
+/// use std::{
+///     fs,
+///     sync::atomic::{AtomicUsize, Ordering},
+/// };
+///
+/// let dir = std::env::temp_dir().join("cleanrust-fn-observer");
+/// fs::create_dir_all(dir.join("target"))?;
+/// fs::write(dir.join("Cargo.toml"), "")?;
+/// let removed = AtomicUsize::new(0);
+/// let observer = FnObserver::new().with_removal(|_, _, _| {
+///     removed.fetch_add(1, Ordering::SeqCst);
+/// });
+/// clean(&[&dir], &CleanOptions::default(), &observer);
+/// assert_eq!(removed.load(Ordering::SeqCst), 1);
+/// fs::remove_dir_all(&dir)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Default)]
+pub struct FnObserver<'a> {
+    on_error: Option<ErrorCallback<'a>>,
+    on_removal: Option<RemovalCallback<'a>>,
+    on_scanned: Option<ScannedCallback<'a>>,
+}
+
+/// Closure called by [`FnObserver`] on errors
+type ErrorCallback<'a> = Box<dyn Fn(&Path, io::Error) + Sync + 'a>;
+
+/// Closure called by [`FnObserver`] on removals
+type RemovalCallback<'a> = Box<dyn Fn(&Path, &Path, Option<u64>) + Sync + 'a>;
+
+/// Closure called by [`FnObserver`] on scanned directories
+type ScannedCallback<'a> = Box<dyn Fn(&Path) + Sync + 'a>;
+
+impl<'a> FnObserver<'a> {
+    /// Returns an observer ignoring all events
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `f` with the path and error when an error occurs
+    pub fn with_error(mut self, f: impl Fn(&Path, io::Error) + Sync + 'a) -> Self {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` with the path removed, the package directory and the size freed, if known,
+    /// after each removal
+    pub fn with_removal(mut self, f: impl Fn(&Path, &Path, Option<u64>) + Sync + 'a) -> Self {
+        self.on_removal = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` with each directory scanned
+    pub fn with_scanned(mut self, f: impl Fn(&Path) + Sync + 'a) -> Self {
+        self.on_scanned = Some(Box::new(f));
+        self
+    }
+}
+
+impl Observer for FnObserver<'_> {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        if let Some(f) = &self.on_error {
+            f(path, e)
+        }
+    }
+
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        if let Some(f) = &self.on_removal {
+            f(path, package_dir, size)
+        }
+    }
+
+    fn on_scanned(&self, dir: &Path) {
+        if let Some(f) = &self.on_scanned {
+            f(dir)
+        }
+    }
+
+    fn on_would_remove(&self, _: &Path, _: &Path, _: Option<u64>) {}
+
+    fn on_warning(&self, _: &Path, _: &str) {}
+
+    fn on_skipped(&self, _: &Path, _: SkipReason) {}
+}
+
+/// Observer forwarding to another one while counting events for the final summary
+struct Tally<O> {
+    observer: O,
+    scanned: AtomicUsize,
+    removed: AtomicUsize,
+    skipped: AtomicUsize,
+    locked: AtomicUsize,
+    pinned: AtomicUsize,
+    errors: AtomicUsize,
+    freed_bytes: Option<AtomicU64>,
+    /// Number of files and directories removed, if measured
+    removed_entries: Option<(AtomicU64, AtomicU64)>,
+    /// Directories removed, if collected
+    removed_paths: Option<Mutex<Vec<PathBuf>>>,
+}
+
+impl<O> Tally<O> {
+    fn new(observer: O, options: &CleanOptions) -> Self {
+        Self {
+            observer,
+            scanned: Default::default(),
+            removed: Default::default(),
+            skipped: Default::default(),
+            locked: Default::default(),
+            pinned: Default::default(),
+            errors: Default::default(),
+            freed_bytes: options.measure.then(Default::default),
+            removed_entries: options.measure.then(Default::default),
+            removed_paths: options.collect_removed.then(Default::default),
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            scanned: self.scanned.load(atomic::Ordering::SeqCst),
+            removed: self.removed.load(atomic::Ordering::SeqCst),
+            skipped: self.skipped.load(atomic::Ordering::SeqCst),
+            locked: self.locked.load(atomic::Ordering::SeqCst),
+            pinned: self.pinned.load(atomic::Ordering::SeqCst),
+            errors: self.errors.load(atomic::Ordering::SeqCst),
+            freed_bytes: self
+                .freed_bytes
+                .as_ref()
+                .map(|freed| freed.load(atomic::Ordering::SeqCst)),
+            removed_files: self
+                .removed_entries
+                .as_ref()
+                .map(|(files, _)| files.load(atomic::Ordering::SeqCst)),
+            removed_dirs: self
+                .removed_entries
+                .as_ref()
+                .map(|(_, dirs)| dirs.load(atomic::Ordering::SeqCst)),
+            interrupted: false,
+            timed_out: false,
+            unprocessed: 0,
+            free_space_reached: false,
+            unreadable_roots: 0,
+            scan_errors: 0,
+            timing: Timing::default(),
+            slowest_dirs: Vec::new(),
+            removed_paths: self
+                .removed_paths
+                .as_ref()
+                .map(|paths| std::mem::take(&mut *paths.lock().unwrap()))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn record_removal(&self, path: &Path, size: Option<u64>) {
+        self.removed.fetch_add(1, atomic::Ordering::SeqCst);
+        if let Some(paths) = &self.removed_paths {
+            paths.lock().unwrap().push(path.to_owned());
+        }
+        if let (Some(freed), Some(size)) = (&self.freed_bytes, size) {
+            freed.fetch_add(size, atomic::Ordering::SeqCst);
+        }
+    }
+
+    /// Counts what was freed by failing to remove a directory after removing part of it
+    fn record_partial_removal(&self, freed: DirSize) {
+        if let Some(freed_bytes) = &self.freed_bytes {
+            freed_bytes.fetch_add(freed.bytes, atomic::Ordering::SeqCst);
+        }
+        self.record_contents(Some(freed));
+    }
+
+    /// Counts the files and directories of a removed directory whose contents are `size`
+    fn record_contents(&self, size: Option<DirSize>) {
+        if let (Some((files, dirs)), Some(size)) = (&self.removed_entries, size) {
+            files.fetch_add(size.files, atomic::Ordering::SeqCst);
+            dirs.fetch_add(size.dirs, atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+impl<O: Observer> Observer for Tally<O> {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        self.errors.fetch_add(1, atomic::Ordering::SeqCst);
+        #[cfg(feature = "tracing")]
+        tracing::error!(path = %path.display(), error = %e, "failed");
+        self.observer.on_error(path, e)
+    }
+
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.record_removal(path, size);
+        #[cfg(feature = "tracing")]
+        tracing::info!(path = %path.display(), size, "removed");
+        self.observer.on_removal(path, package_dir, size)
+    }
+
+    fn on_scanned(&self, dir: &Path) {
+        self.scanned.fetch_add(1, atomic::Ordering::SeqCst);
+        self.observer.on_scanned(dir)
+    }
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.record_removal(path, size);
+        #[cfg(feature = "tracing")]
+        tracing::info!(path = %path.display(), size, "would remove");
+        self.observer.on_would_remove(path, package_dir, size)
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(path = %path.display(), message);
+        self.observer.on_warning(path, message)
+    }
+
+    fn on_skipped(&self, path: &Path, reason: SkipReason) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(path = %path.display(), %reason, "skipped");
+        self.skipped.fetch_add(1, atomic::Ordering::SeqCst);
+        match reason {
+            SkipReason::Locked => self.locked.fetch_add(1, atomic::Ordering::SeqCst),
+            SkipReason::Pinned => self.pinned.fetch_add(1, atomic::Ordering::SeqCst),
+            _ => 0,
+        };
+        self.observer.on_skipped(path, reason)
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        self.observer.confirm_removal(path)
+    }
+
+    fn on_measured(&self, path: &Path, size: DirSize) {
+        self.observer.on_measured(path, size)
+    }
+
+    fn on_scan_error(&self, path: &Path, e: io::Error) {
+        self.errors.fetch_add(1, atomic::Ordering::SeqCst);
+        #[cfg(feature = "tracing")]
+        tracing::error!(path = %path.display(), error = %e, "failed to scan");
+        self.observer.on_scan_error(path, e)
+    }
+
+    fn should_remove(&self, path: &Path, package_dir: &Path) -> bool {
+        self.observer.should_remove(path, package_dir)
+    }
+}
+
+/// Returns the roots not nested in other roots, keeping the first of identical roots
+///
+/// Roots whose canonical path cannot be determined are kept.
+fn outermost<P: AsRef<Path>>(roots: &[P]) -> Vec<&Path> {
+    let canonical = roots
+        .iter()
+        .map(|root| canonicalize(root).ok())
+        .collect::<Vec<_>>();
+    let is_nested = |i: usize| {
+        let Some(path) = &canonical[i] else {
+            return false;
+        };
+        canonical.iter().enumerate().any(|(j, other)| {
+            other.as_ref().is_some_and(|other| {
+                if other == path {
+                    j < i
+                } else {
+                    path.starts_with(other)
+                }
+            })
+        })
+    };
+    roots
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| !is_nested(i))
+        .map(|(_, root)| root.as_ref())
+        .collect()
+}
+
+/// Returns what the tree at `root` holds, measuring files according to `mode`, without the
+/// directories for which `skip` returns true, or `None` if `root` cannot be read
+///
+/// Nested entries that cannot be read, e.g. because they vanished in the meantime, are not
+/// counted.
+fn tree_size(root: &Path, mode: SizeMode, skip: impl Fn(&Path) -> bool) -> Option<DirSize> {
+    let mut size = DirSize::default();
+    let mut pending = vec![root.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) if dir == root => return None,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                let path = entry.path();
+                if skip(&path) {
+                    continue;
+                }
+                size.dirs += 1;
+                pending.push(path);
+            } else {
+                size.files += 1;
+                size.bytes += file_size(&metadata, mode);
+            }
+        }
+    }
+    Some(size)
+}
+
+/// Returns the size of the file with `metadata` according to `mode`
+fn file_size(metadata: &Metadata, mode: SizeMode) -> u64 {
+    match mode {
+        SizeMode::Apparent => metadata.len(),
+        // Block counts are in units of 512 bytes whatever the block size of the file system.
+        #[cfg(unix)]
+        SizeMode::Disk => std::os::unix::fs::MetadataExt::blocks(metadata) * 512,
+        #[cfg(not(unix))]
+        SizeMode::Disk => metadata.len(),
+    }
+}
+
+/// Identity of a file, telling whether two paths lead to the same file
+///
+/// On Unix, the device and inode numbers are known from metadata without resolving the path,
+/// which would make scanning deep trees quadratic. Elsewhere, the canonical path is used.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+/// Returns the identity of the file at `path` with `metadata`
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &Metadata) -> Result<FileId, io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// Returns the identity of the file at `path` with `metadata`
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &Metadata) -> Result<FileId, io::Error> {
+    canonicalize(path)
+}
+
+/// What a directory tree holds
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DirSize {
+    /// Total size in bytes of the files
+    pub bytes: u64,
+    /// Number of files, including symbolic links
+    pub files: u64,
+    /// Number of directories, excluding the root of the tree
+    pub dirs: u64,
+}
+
+/// Returns whether the operation failing with `e` may succeed if attempted again
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+/// Removes the directory tree at `dir`
+///
+/// On Windows, the canonical path is used as its `\\?\` prefix lifts the limit on path lengths
+/// that deep trees would otherwise exceed. Read-only files cannot be deleted either, so if
+/// removal is denied, the read-only attribute is cleared throughout the tree and removal is
+/// attempted once more.
+fn remove_tree(dir: &Path) -> Result<(), io::Error> {
+    #[cfg(windows)]
+    let canonical = canonicalize(dir);
+    #[cfg(windows)]
+    let dir = canonical.as_deref().unwrap_or(dir);
+    match remove_dir_all(dir) {
+        #[cfg(windows)]
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            clear_readonly(dir);
+            remove_dir_all(dir)
+        }
+        result => result,
+    }
+}
+
+/// Clears the read-only attribute of everything under `root`, ignoring entries that cannot be
+/// updated
+#[cfg(windows)]
+// Clearing the read-only attribute on Windows does not make files world-writable.
+#[allow(clippy::permissions_set_readonly_false)]
+fn clear_readonly(root: &Path) {
+    let mut pending = vec![root.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            }
+            let mut permissions = metadata.permissions();
+            if permissions.readonly() {
+                permissions.set_readonly(false);
+                let _ = std::fs::set_permissions(entry.path(), permissions);
+            }
+        }
+    }
+}
+
+/// Returns whether `dir` is the root of a git repository
+fn is_git_repo(dir: &Path) -> bool {
+    // `.git` is a file in worktrees and submodules.
+    dir.join(".git").exists()
+}
+
+/// Returns whether a build currently holds a lock in `target`
+///
+/// Cargo locks `.cargo-lock` in each profile directory while building. A lock file that can be
+/// acquired is stale.
+fn is_locked(target: &Path) -> bool {
+    let profile_dirs = read_dir(target)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path());
+    std::iter::once(target.to_owned())
+        .chain(profile_dirs)
+        .any(|dir| match File::open(dir.join(".cargo-lock")) {
+            Ok(file) => matches!(file.try_lock(), Err(TryLockError::WouldBlock)),
+            Err(_) => false,
+        })
+}
+
+/// Returns whether the package in `package_dir` is pinned to a toolchain
+fn is_pinned(package_dir: &Path) -> bool {
+    ["rust-toolchain", "rust-toolchain.toml"]
+        .iter()
+        .any(|name| package_dir.join(name).is_file())
+}
+
+/// `.gitignore` rules applying to a directory, from the nearest file up
+#[derive(Debug)]
+struct IgnoreStack {
+    matcher: Gitignore,
+    parent: Option<Arc<IgnoreStack>>,
+}
+
+impl IgnoreStack {
+    /// Returns whether the directory at `path` is ignored
+    ///
+    /// As with git, rules in nearer files take precedence.
+    fn is_ignored(&self, path: &Path) -> bool {
+        let mut stack = Some(self);
+        while let Some(ignores) = stack {
+            match ignores.matcher.matched(path, true) {
+                Match::None => stack = ignores.parent.as_deref(),
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
+
+/// Target directory to remove
+#[derive(Debug)]
+struct Removal {
+    target: PathBuf,
+    /// Directory of the package the target directory belongs to
+    package_dir: PathBuf,
+    /// Whether this is the `target` directory next to the manifest, which `cargo clean` removes
+    is_target: bool,
+    /// Contents, if already measured
+    size: Option<DirSize>,
+}
+
+/// Cargo workspace, whose members share the target directory at its root
+///
+/// Members are not scanned since their own target directories are not used.
+#[derive(Debug)]
+struct Workspace {
+    root: PathBuf,
+    members: GlobSet,
+    excluded: GlobSet,
+}
+
+impl Workspace {
+    /// Parses the manifest of the package in `dir`, returning the workspace it defines, if any
+    fn from_manifest(dir: &Path, manifest: &str) -> Result<Option<Self>, String> {
+        let manifest = manifest.parse::<toml::Table>().map_err(|e| e.to_string())?;
+        let Some(workspace) = manifest.get("workspace") else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            root: dir.to_owned(),
+            members: member_globs(workspace, "members")?,
+            excluded: member_globs(workspace, "exclude")?,
+        }))
+    }
+
+    fn is_member(&self, dir: &Path) -> bool {
+        let Ok(path) = dir.strip_prefix(&self.root) else {
+            return false;
+        };
+        self.members.is_match(path) && !self.excluded.is_match(path)
+    }
+}
+
+/// Returns the package name in `manifest`, if any
+fn package_name(manifest: &str) -> Result<Option<String>, String> {
+    let manifest = manifest.parse::<toml::Table>().map_err(|e| e.to_string())?;
+    let Some(name) = manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+    else {
+        return Ok(None);
+    };
+    match name.as_str() {
+        Some(name) => Ok(Some(name.to_owned())),
+        None => Err("`package.name` is not a string".to_owned()),
+    }
+}
+
+/// Returns the globs matching the member paths listed under `key` in a `[workspace]` table
+fn member_globs(workspace: &toml::Value, key: &str) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    let paths = match workspace.get(key) {
+        Some(paths) => paths
+            .as_array()
+            .ok_or_else(|| format!("`workspace.{key}` is not an array"))?,
+        None => return builder.build().map_err(|e| e.to_string()),
+    };
+    for path in paths {
+        let path = path
+            .as_str()
+            .ok_or_else(|| format!("`workspace.{key}` contains a non-string"))?;
+        builder.add(member_glob(path).map_err(|e| e.to_string())?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Returns the glob matching a workspace member path given in a manifest
+fn member_glob(path: &str) -> Result<Glob, globset::Error> {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    GlobBuilder::new(path.trim_end_matches('/'))
+        .literal_separator(true)
+        .build()
+}
+
+/// Directory to scan along with the context inherited from its ancestors
+#[derive(Debug)]
+struct ScanJob {
+    dir: PathBuf,
+    /// Identity of the directory, if already known
+    id: Option<FileId>,
+    /// Depth relative to the scanned root
+    depth: usize,
+    ignores: Option<Arc<IgnoreStack>>,
+    /// Nearest enclosing workspace
+    workspace: Option<Arc<Workspace>>,
+    /// Whether the directory is known to be in a git repository
+    in_git_repo: bool,
+    scans: Sender<ScanJob>,
+    removals: Sender<Removal>,
+}
+
+impl ScanJob {
+    fn child(&self, dir: PathBuf, id: Option<FileId>) -> Self {
+        Self {
+            dir,
+            id,
+            depth: self.depth + 1,
+            ignores: self.ignores.clone(),
+            workspace: self.workspace.clone(),
+            in_git_repo: self.in_git_repo,
+            scans: self.scans.clone(),
+            removals: self.removals.clone(),
+        }
+    }
+}
+
+/// Time spent by a pool of workers, in nanoseconds
+#[derive(Debug, Default)]
+struct PoolTimes {
+    busy: AtomicU64,
+    idle: AtomicU64,
+}
+
+impl PoolTimes {
+    /// Calls `f` with each job from `jobs`, recording the time spent in `f` as busy and the time
+    /// spent waiting for jobs as idle
+    fn track<T>(&self, jobs: impl IntoIterator<Item = T>, mut f: impl FnMut(T)) {
+        let add = |counter: &AtomicU64, time: Duration| {
+            let nanos = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+            counter.fetch_add(nanos, atomic::Ordering::SeqCst);
+        };
+        let mut waiting_since = Instant::now();
+        for job in jobs {
+            let started = Instant::now();
+            add(&self.idle, started - waiting_since);
+            f(job);
+            waiting_since = Instant::now();
+            add(&self.busy, waiting_since - started);
+        }
+        add(&self.idle, waiting_since.elapsed());
+    }
+
+    fn busy(&self) -> Duration {
+        Duration::from_nanos(self.busy.load(atomic::Ordering::SeqCst))
+    }
+
+    fn idle(&self) -> Duration {
+        Duration::from_nanos(self.idle.load(atomic::Ordering::SeqCst))
+    }
+}
+
+/// Target directory found while removals are deferred
+#[derive(Debug)]
+struct Candidate {
+    removal: Removal,
+    modified: Option<SystemTime>,
+    /// Last access time, if needed for ranking
+    accessed: Option<SystemTime>,
+}
+
+impl Candidate {
+    /// Returns how worth removing the target directory is, based on its size and how long it
+    /// went unused
+    ///
+    /// Missing times count as just now, and a missing size as zero.
+    fn staleness(&self, now: SystemTime) -> f64 {
+        let idle = self
+            .accessed
+            .or(self.modified)
+            .and_then(|time| now.duration_since(time).ok())
+            .unwrap_or_default();
+        self.removal.size.map_or(0, |size| size.bytes) as f64 * idle.as_secs_f64()
+    }
+}
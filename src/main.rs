@@ -1,232 +1,2339 @@
 // Copyright (C) 2023 Stephane Raux. Distributed under the 0BSD license.
 
-//! # Overview
-//! - [⚖ 0BSD license](https://spdx.org/licenses/0BSD.html)
-//!
-//! CLI tool to clean Rust build artifacts.
-//!
-//! # Contribute
-//! All contributions shall be licensed under the [0BSD license](https://spdx.org/licenses/0BSD.html).
-
 #![deny(warnings)]
 
-use clap::Parser;
-use crossbeam::channel::Sender;
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::{
+    parser::ValueSource, ArgAction, ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser,
+    ValueEnum,
+};
+use cleanrust::{clean, CleanOptions, DirSize, Observer, ScanOrder, SizeMode, SkipReason, Summary};
+use console::style;
+use globset::{Glob, GlobSetBuilder};
+use indicatif::{style::TemplateError, HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{read_dir, remove_dir_all, DirEntry},
-    io,
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
-    process::ExitCode,
-    sync::atomic::{self, AtomicUsize},
-    thread,
+    process::{self, Command, ExitCode, ExitStatus},
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 /// Clean Rust build artifacts
+///
+/// Flags can also be set in a `cleanrust.toml` file in the current directory or in
+/// `$XDG_CONFIG_HOME`, with keys named after the long flags (e.g. `older-than = "7d"`). Flags given
+/// on the command line take precedence, and `--no-<flag>` turns off a flag set in the file.
 #[derive(Debug, Parser)]
-#[command(author, version, about)]
+#[command(author, version, about, args_override_self = true, after_help = EXIT_CODES)]
+#[command(group(ArgGroup::new("path_list").args(["list", "dry_run"]).multiple(true)))]
+#[command(group(
+    ArgGroup::new("removing_nothing")
+        .args(["dry_run", "list", "stats_only"])
+        .multiple(true)
+))]
 struct Args {
-    /// Number of concurrent jobs
-    #[arg(long, default_value_t = 8)]
-    concurrency: usize,
-    /// Directory to scan recursively for build artifacts
-    dir: PathBuf,
+    /// Read flags from this file instead of searching for `cleanrust.toml`
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Number of concurrent jobs scanning and removing each, at most 1024
+    /// [default: twice the available parallelism]
+    #[arg(long, value_parser = parse_worker_count)]
+    concurrency: Option<usize>,
+    /// Number of concurrent jobs scanning directories, overriding --concurrency
+    #[arg(long, value_parser = parse_worker_count)]
+    scan_threads: Option<usize>,
+    /// Number of concurrent jobs removing directories, overriding --concurrency
+    ///
+    /// Scanning stays parallel when this is low, e.g. 1 to avoid thrashing a spinning disk.
+    #[arg(long, visible_alias = "remove-concurrency", value_parser = parse_worker_count)]
+    remove_threads: Option<usize>,
+    /// Report what would be removed without removing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Measure the disk space freed by removing build artifacts
+    #[arg(long)]
+    measure: bool,
+    /// How to measure sizes, `disk` counting allocated blocks like `du` on Unix
+    #[arg(long, value_enum, default_value_t = Sizes::Apparent)]
+    size_mode: Sizes,
+    /// Only remove build artifacts not modified for this long (e.g. 30m, 48h, 7d, 2w)
+    #[arg(long, value_parser = parse_duration)]
+    older_than: Option<Duration>,
+    /// Only remove build artifacts not accessed for this long (e.g. 30m, 48h, 7d, 2w)
+    ///
+    /// Access times may be stale on file systems mounted with `noatime`.
+    #[arg(long, value_parser = parse_duration)]
+    accessed_before: Option<Duration>,
+    /// Leave in place target directories modified after the Cargo.lock of their package, or
+    /// its manifest if there is no lock file
+    #[arg(long)]
+    exclude_newer_than_manifest: bool,
+    /// Ask for confirmation before each removal
+    #[arg(short, long)]
+    interactive: bool,
+    /// Only ask for confirmation before removing directories larger than this (e.g. 10G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    confirm_over: Option<u64>,
+    /// Skip paths matching this glob, relative to the scanned directory (e.g. vendor/**)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<Glob>,
+    /// Skip paths matching the globs listed in this file, one per line
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Option<PathBuf>,
+    /// Leave alone packages whose directory matches this glob, relative to the scanned directory
+    /// (e.g. work/*)
+    ///
+    /// Their subdirectories are not scanned either.
+    #[arg(long, value_name = "GLOB")]
+    exclude_project: Vec<Glob>,
+    /// Only remove target directories matching this glob, relative to the scanned directory
+    /// (e.g. projects/**)
+    ///
+    /// Directories that cannot hold matching paths are not scanned.
+    #[arg(long, value_name = "GLOB")]
+    only: Vec<Glob>,
+    /// Do not descend into directories ignored by .gitignore files
+    ///
+    /// Target directories are still removed even though they are usually ignored.
+    #[arg(long)]
+    respect_gitignore: bool,
+    /// Maximum depth of directories to scan, 0 meaning only the given directory
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// Scan symlinked directories and remove what symlinked target directories point to
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Run `cargo clean` in each package instead of removing its target directory
+    #[arg(long)]
+    cargo_clean: bool,
+    /// Move build artifacts to the trash instead of deleting them
+    #[cfg(feature = "trash")]
+    #[arg(long, conflicts_with = "cargo_clean")]
+    trash: bool,
+    /// Fail instead of deleting directories that cannot be moved to the trash
+    #[cfg(feature = "trash")]
+    #[arg(long, requires = "trash")]
+    strict: bool,
+    /// Fail to delete directories matching this glob (e.g. **/foo/target), for tests only
+    #[cfg(feature = "testing")]
+    #[arg(long, value_name = "GLOB", hide = true)]
+    simulate_errors: Vec<Glob>,
+    /// Target directory shared by packages, removed once if any package is found
+    #[arg(long, env = "CARGO_TARGET_DIR")]
+    target_dir: Option<PathBuf>,
+    /// Remove target directories even if a build is using them
+    #[arg(long)]
+    force: bool,
+    /// Name of the manifest file identifying packages
+    #[arg(long, value_name = "NAME", default_value = "Cargo.toml")]
+    manifest_name: String,
+    /// Also remove directories with this name next to a manifest, like `target`
+    #[arg(long, value_name = "NAME")]
+    extra: Vec<String>,
+    /// Also remove the directory whose path, relative to the package, is in a file with this
+    /// name next to a manifest (e.g. .cargo_target)
+    #[arg(long, value_name = "NAME")]
+    target_marker: Option<String>,
+    /// Only remove build artifacts of the package with this name
+    #[arg(long, value_name = "NAME")]
+    package: Vec<String>,
+    /// Only remove build artifacts of packages in git repositories found at or below the
+    /// directories to clean
+    #[arg(long)]
+    git_only: bool,
+    /// Only remove build artifacts of packages without changes since this git revision
+    #[arg(long, value_name = "REV")]
+    unchanged_since: Option<String>,
+    /// Profile whose build artifacts to remove, `all` removing whole target directories
+    #[arg(long, value_enum, default_value_t = Profile::All)]
+    profile: Profile,
+    /// Only remove the largest and least recently used target directories needed to free this
+    /// much space (e.g. 500K, 100M, 1G)
+    #[arg(long, value_parser = parse_size)]
+    reclaim: Option<u64>,
+    /// Stop removing target directories once this much space is available on their file system
+    /// (e.g. 20G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_free_space: Option<u64>,
+    /// Leave the N most recently modified target directories in place
+    #[arg(long, value_name = "N")]
+    keep_recent: Option<usize>,
+    /// Remove the largest target directories first, e.g. to meet --reclaim in fewer removals
+    ///
+    /// Nothing is removed until scanning completes.
+    #[arg(long)]
+    biggest_first: bool,
+    /// Report errors while scanning without failing, e.g. for unreadable directories
+    #[arg(long)]
+    ignore_scan_errors: bool,
+    /// Only remove target directories making up at least this percentage of their package
+    #[arg(long, value_name = "PCT", value_parser = parse_percentage)]
+    target_ratio: Option<f64>,
+    /// Leave the target directories of workspace roots in place, removing those of members
+    #[arg(long)]
+    keep_workspace_root: bool,
+    /// Also scan workspace members, e.g. nested workspaces, removing target directories they hold
+    /// of their own
+    #[arg(long)]
+    nested: bool,
+    /// Also scan hidden directories, whose name starts with a dot, e.g. `.git`
+    #[arg(long)]
+    include_hidden: bool,
+    /// Order in which to scan directories, best-effort since workers scan concurrently
+    #[arg(long, value_enum, default_value_t = Order::Bfs)]
+    order: Order,
+    /// How to print paths, as given by default
+    #[arg(long, value_enum, value_name = "STYLE")]
+    paths: Option<PathStyle>,
+    /// Maximum number of directories waiting to be scanned or removed, to bound memory use
+    #[arg(long, value_name = "N")]
+    queue_bound: Option<usize>,
+    /// Leave empty target directories in place
+    #[arg(long)]
+    skip_empty: bool,
+    /// Stop cleaning after this long, letting ongoing removals finish (e.g. 30s, 10m)
+    #[arg(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+    /// Stop cleaning at the first error, letting ongoing removals finish
+    #[arg(long)]
+    fail_fast: bool,
+    /// Check that target directories no longer exist once removed, reporting an error otherwise
+    #[arg(long)]
+    verify: bool,
+    /// Leave in place the target directories of packages pinned to a toolchain by a
+    /// rust-toolchain or rust-toolchain.toml file
+    #[arg(long)]
+    keep_pinned: bool,
+    /// Print the time workers spent busy and idle, summed over each pool, to tune concurrency
+    #[arg(long)]
+    debug_timing: bool,
+    /// Print the scan rate and the N directories slowest to read, 10 by default
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "10")]
+    profile_scan: Option<usize>,
+    /// Number of times to retry removals failing with transient errors, e.g. on network
+    /// file systems
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    /// Leave target directories smaller than this in place (e.g. 500K, 100M, 1G)
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+    /// Only print errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print each removed directory, and each scanned directory if repeated
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Print removed directories sorted once done instead of as they are removed
+    #[arg(long, conflicts_with = "quiet")]
+    sorted_summary: bool,
+    /// List the N packages freeing the most space at the end, measuring sizes
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+    /// Only print the paths of the target directories that would be removed, one per line
+    ///
+    /// Sizes follow paths after a tab if measured. Nothing is removed.
+    #[arg(
+        long,
+        conflicts_with_all = ["format", "interactive", "confirm_over", "progress", "quiet", "verbose"]
+    )]
+    list: bool,
+    /// Terminate the paths printed by --list with NUL bytes instead of newlines, e.g. for
+    /// `xargs -0`
+    ///
+    /// With --dry-run, the paths that would be removed are printed this way to stdout instead of
+    /// being listed along with the summary.
+    #[arg(long, requires = "path_list", conflicts_with = "format")]
+    print0: bool,
+    /// Only print how much space removing the target directories would free
+    ///
+    /// Nothing is removed.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "format",
+            "interactive",
+            "confirm_over",
+            "progress",
+            "quiet",
+            "verbose",
+            "list"
+        ]
+    )]
+    stats_only: bool,
+    /// Measure target directories first to show a progress bar of the space freed instead of a
+    /// spinner
+    ///
+    /// This scans and measures the directory tree twice.
+    #[arg(long)]
+    progress: bool,
+    /// Template of the progress indicator, as documented by the `indicatif` crate
+    ///
+    /// e.g. "{spinner} {bytes}/{total_bytes} {bytes_per_sec} {eta} {msg}"
+    #[arg(long, value_parser = parse_template)]
+    template: Option<String>,
+    /// Write the absolute paths of the removed directories to this file, one per line
+    #[arg(long, value_name = "FILE", conflicts_with = "removing_nothing")]
+    output: Option<PathBuf>,
+    /// Write a JSON manifest of the removed directories and their packages to this file
+    ///
+    /// Building the listed packages again restores what was cleaned.
+    #[arg(long, value_name = "FILE", conflicts_with = "removing_nothing")]
+    undo_manifest: Option<PathBuf>,
+    /// Compare a dry run, including --list and --stats-only, to an undo manifest written by a
+    /// previous run
+    ///
+    /// Reports how many packages have build artifacts for the first time, have them again
+    /// since being cleaned, and no longer exist.
+    #[arg(long, value_name = "FILE", requires = "removing_nothing")]
+    compare: Option<PathBuf>,
+    /// Write the totals of the run as JSON to this file once done, whatever the format
+    #[arg(long, value_name = "FILE")]
+    summary_json: Option<PathBuf>,
+    /// Print how many directories were scanned and removed and how many errors occurred in each
+    /// directory to clean once done
+    #[arg(long)]
+    per_root: bool,
+    /// Also write each event as a JSON object on a line of its own to the Unix domain socket at
+    /// this path, as `--format ndjson` does to stdout
+    ///
+    /// Cleaning goes on with a warning if the socket cannot be connected to.
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["list", "stats_only"])]
+    socket: Option<PathBuf>,
+    /// Run this shell command once done
+    ///
+    /// CLEANRUST_REMOVED, CLEANRUST_ERRORS and, if measured, CLEANRUST_FREED_BYTES hold the
+    /// totals of the run.
+    #[arg(long, value_name = "CMD")]
+    after: Option<String>,
+    /// Fail if the command run by --after fails
+    #[arg(long, requires = "after")]
+    strict_hooks: bool,
+    /// When to use colors, `auto` disabling them if NO_COLOR is set
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Keep directories to clean as given instead of making them absolute
+    #[arg(long)]
+    no_canonicalize: bool,
+    /// Also read directories to clean from stdin, one per line
+    ///
+    /// A directory named `-` does the same.
+    #[arg(long, conflicts_with_all = ["interactive", "confirm_over"])]
+    from_stdin: bool,
+    /// Skip directories to clean that do not exist and count build artifacts removed by another
+    /// process meanwhile as removed
+    #[arg(long)]
+    ignore_missing: bool,
+    /// Directories to scan recursively for build artifacts
+    #[arg(required_unless_present = "from_stdin")]
+    dirs: Vec<PathBuf>,
 }
 
 fn main() -> ExitCode {
-    let args = Args::parse();
-    let status = Status::new();
-    let success = clean_dir(&args.dir, args.concurrency, &status);
-    status.indicator.finish();
-    if success {
+    let mut args = match args_with_config() {
+        Ok(args) => match command()
+            .try_get_matches_from(args)
+            .and_then(|matches| Args::from_arg_matches(&matches))
+        {
+            Ok(args) => args,
+            Err(e) => {
+                let _ = e.print();
+                return if e.use_stderr() {
+                    ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE)
+                } else {
+                    ExitCode::SUCCESS
+                };
+            }
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+        }
+    };
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .init();
+    let colors = match args.color {
+        ColorChoice::Auto => std::env::var_os("NO_COLOR")
+            .is_some_and(|no_color| !no_color.is_empty())
+            .then_some(false),
+        ColorChoice::Always => Some(true),
+        ColorChoice::Never => Some(false),
+    };
+    if let Some(colors) = colors {
+        console::set_colors_enabled(colors);
+        console::set_colors_enabled_stderr(colors);
+    }
+    if args.from_stdin || args.dirs.iter().any(|dir| dir == Path::new("-")) {
+        match stdin_dirs() {
+            Ok(dirs) => {
+                args.dirs.retain(|dir| dir != Path::new("-"));
+                args.dirs.extend(dirs);
+            }
+            Err(e) => {
+                eprintln!("Failed to read directories from stdin: {e}");
+                return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+            }
+        }
+    }
+    let mut dirs = Vec::with_capacity(args.dirs.len());
+    for dir in &args.dirs {
+        match root_dir(dir, !args.no_canonicalize) {
+            Ok(root) => dirs.push(root),
+            Err(e) if args.ignore_missing && e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) if e.kind() == io::ErrorKind::NotADirectory => {
+                eprintln!("error: {} is not a directory", dir.display());
+                return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+            }
+            Err(e) => {
+                eprintln!("{}: {e}", dir.display());
+                return ExitCode::from(UNREADABLE_ROOT_EXIT_CODE);
+            }
+        }
+    }
+    args.dirs = dirs;
+    let mut exclude = GlobSetBuilder::new();
+    for glob in &args.exclude {
+        exclude.add(glob.clone());
+    }
+    if let Some(path) = &args.exclude_from {
+        match read_globs(path) {
+            Ok(globs) => {
+                for glob in globs {
+                    exclude.add(glob);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+            }
+        }
+    }
+    let exclude = match exclude.build() {
+        Ok(exclude) => exclude,
+        Err(e) => {
+            eprintln!("Invalid exclude patterns: {e}");
+            return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+        }
+    };
+    let mut exclude_projects = GlobSetBuilder::new();
+    for glob in &args.exclude_project {
+        exclude_projects.add(glob.clone());
+    }
+    let exclude_projects = match exclude_projects.build() {
+        Ok(exclude_projects) => exclude_projects,
+        Err(e) => {
+            eprintln!("Invalid project exclude patterns: {e}");
+            return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+        }
+    };
+    #[cfg(feature = "testing")]
+    {
+        let mut failing = GlobSetBuilder::new();
+        for glob in &args.simulate_errors {
+            failing.add(glob.clone());
+        }
+        match failing.build() {
+            Ok(failing) => {
+                let _ = SIMULATED_ERRORS.set(failing);
+            }
+            Err(e) => {
+                eprintln!("Invalid simulated error patterns: {e}");
+                return ExitCode::from(INVALID_ARGUMENTS_EXIT_CODE);
+            }
+        }
+    }
+    let defaults = CleanOptions::default();
+    let options = CleanOptions {
+        scan_worker_count: args
+            .scan_threads
+            .or(args.concurrency)
+            .unwrap_or(defaults.scan_worker_count),
+        remove_worker_count: args
+            .remove_threads
+            .or(args.concurrency)
+            .unwrap_or(defaults.remove_worker_count),
+        dry_run: args.dry_run || args.list || args.stats_only,
+        measure: args.measure
+            || args.top.is_some()
+            || args.reclaim.is_some()
+            || args.stats_only
+            || args.progress,
+        older_than: args.older_than,
+        accessed_before: args.accessed_before,
+        exclude_newer_than_manifest: args.exclude_newer_than_manifest,
+        exclude,
+        exclude_projects,
+        only: args.only.clone(),
+        respect_gitignore: args.respect_gitignore,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks,
+        cargo_clean: args.cargo_clean,
+        target_dir: args.target_dir.clone(),
+        ignore_locks: args.force,
+        min_size: args.min_size,
+        stop: Some(Arc::new(AtomicBool::new(false))),
+        retries: args.retries,
+        keep_recent: args.keep_recent,
+        biggest_first: args.biggest_first,
+        reclaim: args.reclaim,
+        min_free_space: args.min_free_space,
+        extra_dirs: args.extra.clone(),
+        target_marker: args.target_marker.clone(),
+        queue_bound: args.queue_bound,
+        skip_empty: args.skip_empty,
+        packages: args.package.clone(),
+        unchanged_since: args.unchanged_since.clone(),
+        // A deadline too far to represent is never reached. Measuring sizes for the progress bar
+        // counts towards it.
+        deadline: args
+            .max_runtime
+            .and_then(|max_runtime| Instant::now().checked_add(max_runtime)),
+        git_only: args.git_only,
+        manifest_name: args.manifest_name.clone(),
+        confirm_over: args.confirm_over,
+        collect_removed: false,
+        include_hidden: args.include_hidden,
+        keep_workspace_root: args.keep_workspace_root,
+        nested: args.nested,
+        fail_fast: args.fail_fast,
+        verify: args.verify,
+        ignore_missing: args.ignore_missing,
+        slowest_dirs: args.profile_scan,
+        size_mode: match args.size_mode {
+            Sizes::Apparent => SizeMode::Apparent,
+            Sizes::Disk => SizeMode::Disk,
+        },
+        keep_pinned: args.keep_pinned,
+        target_ratio: args.target_ratio.map(|percent| percent / 100.0),
+        order: match args.order {
+            Order::Bfs => ScanOrder::BreadthFirst,
+            Order::Dfs => ScanOrder::DepthFirst,
+        },
+        #[cfg(feature = "trash")]
+        trash: args.trash,
+        #[cfg(feature = "trash")]
+        strict_trash: args.strict,
+        #[cfg(feature = "testing")]
+        remover: failing_if_simulated,
+        profile: match args.profile {
+            Profile::Debug => Some("debug".to_owned()),
+            Profile::Release => Some("release".to_owned()),
+            Profile::All => None,
+        },
+    };
+    if let Some(stop) = options.stop.clone() {
+        let installed = ctrlc::set_handler(move || {
+            // A second interruption exits right away in case cleaning is stuck, e.g. waiting
+            // for confirmation.
+            if stop.swap(true, atomic::Ordering::SeqCst) {
+                process::exit(INTERRUPTED_EXIT_CODE.into());
+            }
+        });
+        if let Err(e) = installed {
+            eprintln!("Failed to handle interruptions: {e}");
+        }
+    }
+    // An invalid template is reported before anything is done.
+    let status = match args.format {
+        Format::Human if !args.list && !args.stats_only => match Status::new(&args) {
+            Ok(status) => Some(status),
+            Err(e) => {
+                eprintln!("Invalid template: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+    let log = match args.output.as_deref().map(RemovalLog::create).transpose() {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Failed to create output file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let manifest = match args
+        .undo_manifest
+        .as_deref()
+        .map(UndoManifest::create)
+        .transpose()
+    {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("Failed to create undo manifest: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let comparison = match args.compare.as_deref().map(Comparison::load).transpose() {
+        Ok(comparison) => comparison,
+        Err(e) => {
+            eprintln!("Failed to read previous manifest: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let per_root = args.per_root.then(|| PerRoot::new(&args.dirs));
+    #[cfg(unix)]
+    let socket = args
+        .socket
+        .as_deref()
+        .and_then(|path| match EventStream::connect(path, &args) {
+            Ok(events) => Some(events),
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to connect to {}: {e}",
+                    style("Warning:").yellow().for_stderr(),
+                    path.display()
+                );
+                None
+            }
+        });
+    #[cfg(not(unix))]
+    let socket = None::<EventStream>;
+    let start = Instant::now();
+    // The summary file is written even if writing the output fails.
+    let mut output_error = None;
+    let summary = match args.format {
+        _ if args.list => {
+            let list = List {
+                paths: true,
+                print0: args.print0,
+            };
+            let observer = Logged::new(
+                Rendered::new(list, &args),
+                log.as_ref(),
+                manifest.as_ref(),
+                comparison.as_ref(),
+                per_root.as_ref(),
+                socket.as_ref(),
+            );
+            clean(&args.dirs, &options, observer)
+        }
+        _ if args.stats_only => {
+            let list = List {
+                paths: false,
+                print0: false,
+            };
+            let observer = Logged::new(
+                list,
+                log.as_ref(),
+                manifest.as_ref(),
+                comparison.as_ref(),
+                per_root.as_ref(),
+                socket.as_ref(),
+            );
+            let summary = clean(&args.dirs, &options, observer);
+            print_ignoring_errors(io::stdout(), |out| {
+                writeln!(
+                    out,
+                    "Reclaimable: {} in {} target directories",
+                    HumanBytes(summary.freed_bytes.unwrap_or(0)),
+                    summary.removed
+                )
+            });
+            summary
+        }
+        Format::Human => {
+            let status = status.expect("Status is created for human output");
+            if args.progress {
+                status.indicator.set_message("Measuring target directories");
+                let measure_options = CleanOptions {
+                    dry_run: true,
+                    ..options.clone()
+                };
+                let total = clean(&args.dirs, &measure_options, Silent).freed_bytes;
+                status.show_progress(total.unwrap_or(0));
+            }
+            let observer = Logged::new(
+                Rendered::new(&status, &args),
+                log.as_ref(),
+                manifest.as_ref(),
+                comparison.as_ref(),
+                per_root.as_ref(),
+                socket.as_ref(),
+            );
+            let summary = clean(&args.dirs, &options, observer);
+            status.finish(&summary);
+            summary
+        }
+        Format::Json => {
+            let report = JsonReport::new(&args);
+            let observer = Logged::new(
+                Rendered::new(&report, &args),
+                log.as_ref(),
+                manifest.as_ref(),
+                comparison.as_ref(),
+                per_root.as_ref(),
+                socket.as_ref(),
+            );
+            let summary = clean(&args.dirs, &options, observer);
+            if let Err(e) = report.finish(&summary) {
+                output_error = Some(format!("Failed to write report: {e}"));
+            }
+            summary
+        }
+        Format::Ndjson => {
+            let events = EventStream::new(&args);
+            let observer = Logged::new(
+                Rendered::new(&events, &args),
+                log.as_ref(),
+                manifest.as_ref(),
+                comparison.as_ref(),
+                per_root.as_ref(),
+                socket.as_ref(),
+            );
+            let summary = clean(&args.dirs, &options, observer);
+            if let Err(e) = events.finish(&summary) {
+                output_error = Some(format!("Failed to write events: {e}"));
+            }
+            summary
+        }
+    };
+    if let Some(path) = &args.summary_json {
+        if let Err(e) = write_summary_json(path, &summary, options.dry_run, start.elapsed()) {
+            eprintln!("Failed to write summary: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(e) = output_error {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    if args.debug_timing {
+        let timing = &summary.timing;
+        eprintln!(
+            "Scanners:     {:.2?} busy, {:.2?} idle",
+            timing.scan_busy, timing.scan_idle
+        );
+        eprintln!(
+            "Removers:     {:.2?} busy, {:.2?} idle",
+            timing.remove_busy, timing.remove_idle
+        );
+    }
+    if args.profile_scan.is_some() {
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            eprintln!(
+                "Scan rate:    {:.0} directories/s",
+                summary.scanned as f64 / elapsed
+            );
+        }
+        if !summary.slowest_dirs.is_empty() {
+            eprintln!("Slowest directories:");
+        }
+        for timing in &summary.slowest_dirs {
+            eprintln!(
+                "  {:>10.2?} {:>8} entries  {}",
+                timing.duration,
+                timing.entries,
+                timing.path.display()
+            );
+        }
+    }
+    if let Some(comparison) = &comparison {
+        comparison.report();
+    }
+    if let Some(per_root) = &per_root {
+        per_root.report();
+    }
+    if let Some(Err(e)) = socket.map(|events| events.finish(&summary)) {
+        eprintln!(
+            "{} Failed to write events to the socket: {e}",
+            style("Warning:").yellow().for_stderr()
+        );
+    }
+    if let Some(Err(e)) = log.map(RemovalLog::finish) {
+        eprintln!("Failed to write output file: {e}");
+        return ExitCode::FAILURE;
+    }
+    if let Some(Err(e)) = manifest.map(UndoManifest::finish) {
+        eprintln!("Failed to write undo manifest: {e}");
+        return ExitCode::FAILURE;
+    }
+    if let Some(command) = &args.after {
+        let failure = match run_hook(command, &summary) {
+            Ok(status) if status.success() => None,
+            Ok(status) => Some(status.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(failure) = failure {
+            eprintln!("Command run after cleaning failed: {failure}");
+            if args.strict_hooks {
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if summary.interrupted {
+        ExitCode::from(INTERRUPTED_EXIT_CODE)
+    } else if summary.unreadable_roots > 0 {
+        ExitCode::from(UNREADABLE_ROOT_EXIT_CODE)
+    } else if summary.is_success()
+        || args.ignore_scan_errors && summary.errors == summary.scan_errors
+    {
         ExitCode::SUCCESS
     } else {
         ExitCode::FAILURE
     }
 }
 
-fn clean_dir<O>(dir: &Path, worker_count: usize, observer: O) -> bool
-where
-    O: Observer + Sync,
-{
-    let (sender, receiver) = crossbeam::channel::unbounded();
-    sender
-        .send(Job::Scan(dir.to_owned(), sender.clone()))
-        .unwrap();
-    drop(sender);
-    let has_error = thread::scope(|scope| {
-        let workers = std::iter::repeat_with(|| {
-            scope.spawn(|| {
-                receiver.iter().fold(false, |has_error, job| {
-                    let has_new_error = match job {
-                        Job::Scan(dir, sender) => {
-                            let has_error = match scan(&dir, sender.clone()) {
-                                Ok(jobs) => jobs.fold(false, |has_error, job| match job {
-                                    Ok(job) => sender.send(job).is_err() || has_error,
-                                    Err(e) => {
-                                        observer.on_error(e);
-                                        true
-                                    }
-                                }),
-                                Err(e) => {
-                                    observer.on_error(e);
-                                    true
-                                }
-                            };
-                            observer.on_scanned(&dir);
-                            has_error
-                        }
-                        Job::Remove(path) => match remove_dir_all(&path) {
-                            Ok(()) => {
-                                observer.on_removal(&path);
-                                false
-                            }
-                            Err(e) => {
-                                observer.on_error(e);
-                                true
-                            }
-                        },
-                    };
-                    has_error || has_new_error
-                })
-            })
+/// File listing the removed directories
+struct RemovalLog {
+    file: Mutex<BufWriter<File>>,
+    /// First error writing to the file, after which writing stops
+    error: Mutex<Option<io::Error>>,
+}
+
+impl RemovalLog {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(File::create(path)?)),
+            error: Mutex::new(None),
         })
-        .take(worker_count)
-        .collect::<Vec<_>>();
-        workers.into_iter().any(|worker| worker.join().unwrap())
-    });
-    !has_error
+    }
+
+    fn record(&self, path: &Path) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_some() {
+            return;
+        }
+        let result = std::path::absolute(path).and_then(|path| {
+            let mut file = self.file.lock().unwrap();
+            file.write_all(path.as_os_str().as_encoded_bytes())?;
+            file.write_all(b"\n")
+        });
+        *error = result.err();
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self.error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => self.file.into_inner().unwrap().flush(),
+        }
+    }
+}
+
+/// JSON file listing the removed directories along with their packages
+struct UndoManifest {
+    file: File,
+    entries: Mutex<Vec<ManifestEntry>>,
+}
+
+impl UndoManifest {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+            entries: Default::default(),
+        })
+    }
+
+    fn record(&self, target: &Path, package_dir: &Path, size: Option<u64>) {
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+        self.entries.lock().unwrap().push(ManifestEntry {
+            package_dir: absolute(package_dir),
+            target_dir: absolute(target),
+            freed_bytes: size,
+        });
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let mut file = BufWriter::new(self.file);
+        serde_json::to_writer_pretty(&mut file, &self.entries.into_inner().unwrap())?;
+        writeln!(file)?;
+        file.flush()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ManifestEntry {
+    package_dir: PathBuf,
+    target_dir: PathBuf,
+    freed_bytes: Option<u64>,
+}
+
+/// Packages cleaned by a previous run, compared to the packages a dry run would clean
+struct Comparison {
+    previous: HashSet<PathBuf>,
+    added: AtomicUsize,
+    reappeared: AtomicUsize,
+}
+
+impl Comparison {
+    fn load(path: &Path) -> io::Result<Self> {
+        let entries: Vec<ManifestEntry> =
+            serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(Self {
+            previous: entries.into_iter().map(|entry| entry.package_dir).collect(),
+            added: AtomicUsize::new(0),
+            reappeared: AtomicUsize::new(0),
+        })
+    }
+
+    fn record(&self, package_dir: &Path) {
+        let package_dir =
+            std::path::absolute(package_dir).unwrap_or_else(|_| package_dir.to_owned());
+        let count = if self.previous.contains(&package_dir) {
+            &self.reappeared
+        } else {
+            &self.added
+        };
+        count.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    fn report(&self) {
+        let gone = self.previous.iter().filter(|dir| !dir.exists()).count();
+        eprintln!(
+            "Compared to previous run: {} new, {} reappeared, {} gone",
+            self.added.load(atomic::Ordering::SeqCst),
+            self.reappeared.load(atomic::Ordering::SeqCst),
+            gone,
+        );
+    }
+}
+
+/// Counts of what happened in one directory to clean
+#[derive(Default)]
+struct RootCounts {
+    scanned: AtomicUsize,
+    removed: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+/// Counts of what happened in each directory to clean
+struct PerRoot {
+    roots: Vec<(PathBuf, RootCounts)>,
+}
+
+impl PerRoot {
+    fn new(roots: &[PathBuf]) -> Self {
+        Self {
+            roots: roots
+                .iter()
+                .map(|root| (root.clone(), RootCounts::default()))
+                .collect(),
+        }
+    }
+
+    /// Returns the counts of the outermost directory to clean containing `path`, as nested ones
+    /// are not scanned on their own
+    fn counts(&self, path: &Path) -> Option<&RootCounts> {
+        self.roots
+            .iter()
+            .filter(|(root, _)| path.starts_with(root))
+            .min_by_key(|(root, _)| root.components().count())
+            .map(|(_, counts)| counts)
+    }
+
+    fn record(&self, path: &Path, count: fn(&RootCounts) -> &AtomicUsize) {
+        if let Some(counts) = self.counts(path) {
+            count(counts).fetch_add(1, atomic::Ordering::SeqCst);
+        }
+    }
+
+    fn report(&self) {
+        eprintln!(
+            "{:>8} {:>8} {:>8}  Directory",
+            "Scanned", "Removed", "Errors"
+        );
+        for (root, counts) in &self.roots {
+            eprintln!(
+                "{:>8} {:>8} {:>8}  {}",
+                counts.scanned.load(atomic::Ordering::SeqCst),
+                counts.removed.load(atomic::Ordering::SeqCst),
+                counts.errors.load(atomic::Ordering::SeqCst),
+                root.display()
+            );
+        }
+    }
 }
 
-trait Observer {
-    fn on_error(&self, e: io::Error);
-    fn on_removal(&self, path: &Path);
-    fn on_scanned(&self, dir: &Path);
+/// Observer forwarding to another, recording removals in a [`RemovalLog`] and an
+/// [`UndoManifest`], comparing dry runs to a previous run, counting events per directory to
+/// clean and streaming events to a socket if any
+struct Logged<'a, O> {
+    observer: O,
+    log: Option<&'a RemovalLog>,
+    manifest: Option<&'a UndoManifest>,
+    comparison: Option<&'a Comparison>,
+    per_root: Option<&'a PerRoot>,
+    events: Option<&'a EventStream>,
 }
 
-impl<T: Observer + ?Sized> Observer for &T {
-    fn on_error(&self, e: io::Error) {
-        (**self).on_error(e)
+impl<'a, O> Logged<'a, O> {
+    fn new(
+        observer: O,
+        log: Option<&'a RemovalLog>,
+        manifest: Option<&'a UndoManifest>,
+        comparison: Option<&'a Comparison>,
+        per_root: Option<&'a PerRoot>,
+        events: Option<&'a EventStream>,
+    ) -> Self {
+        Self {
+            observer,
+            log,
+            manifest,
+            comparison,
+            per_root,
+            events,
+        }
+    }
+
+    fn count(&self, path: &Path, count: fn(&RootCounts) -> &AtomicUsize) {
+        if let Some(per_root) = self.per_root {
+            per_root.record(path, count);
+        }
+    }
+}
+
+impl<O: Observer> Observer for Logged<'_, O> {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        self.count(path, |counts| &counts.errors);
+        if let Some(events) = self.events {
+            events.on_error(path, io::Error::new(e.kind(), e.to_string()));
+        }
+        self.observer.on_error(path, e)
     }
 
-    fn on_removal(&self, path: &Path) {
-        (**self).on_removal(path)
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.count(path, |counts| &counts.removed);
+        if let Some(events) = self.events {
+            events.on_removal(path, package_dir, size);
+        }
+        if let Some(log) = self.log {
+            log.record(path);
+        }
+        if let Some(manifest) = self.manifest {
+            manifest.record(path, package_dir, size);
+        }
+        self.observer.on_removal(path, package_dir, size)
     }
 
     fn on_scanned(&self, dir: &Path) {
-        (**self).on_scanned(dir)
+        self.count(dir, |counts| &counts.scanned);
+        if let Some(events) = self.events {
+            events.on_scanned(dir);
+        }
+        self.observer.on_scanned(dir)
+    }
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.count(path, |counts| &counts.removed);
+        if let Some(events) = self.events {
+            events.on_would_remove(path, package_dir, size);
+        }
+        if let Some(comparison) = self.comparison {
+            comparison.record(package_dir);
+        }
+        self.observer.on_would_remove(path, package_dir, size)
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        if let Some(events) = self.events {
+            events.on_warning(path, message);
+        }
+        self.observer.on_warning(path, message)
+    }
+
+    fn on_skipped(&self, path: &Path, reason: SkipReason) {
+        if let Some(events) = self.events {
+            events.on_skipped(path, reason);
+        }
+        self.observer.on_skipped(path, reason)
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        self.observer.confirm_removal(path)
+    }
+
+    fn on_measured(&self, path: &Path, size: DirSize) {
+        self.observer.on_measured(path, size)
+    }
+
+    fn on_scan_error(&self, path: &Path, e: io::Error) {
+        self.count(path, |counts| &counts.errors);
+        if let Some(events) = self.events {
+            events.on_scan_error(path, io::Error::new(e.kind(), e.to_string()));
+        }
+        self.observer.on_scan_error(path, e)
+    }
+
+    fn should_remove(&self, path: &Path, package_dir: &Path) -> bool {
+        self.observer.should_remove(path, package_dir)
+    }
+}
+
+/// Observer forwarding to another with paths rendered in the requested style
+struct Rendered<'a, O> {
+    observer: O,
+    style: Option<PathStyle>,
+    /// Directories to clean, which relative paths are relative to
+    roots: &'a [PathBuf],
+}
+
+impl<'a, O> Rendered<'a, O> {
+    fn new(observer: O, args: &'a Args) -> Self {
+        Self {
+            observer,
+            style: args.paths,
+            roots: &args.dirs,
+        }
+    }
+
+    fn render<'p>(&self, path: &'p Path) -> Cow<'p, Path> {
+        match self.style {
+            None => Cow::Borrowed(path),
+            Some(PathStyle::Absolute) => {
+                std::path::absolute(path).map_or(Cow::Borrowed(path), Cow::Owned)
+            }
+            Some(PathStyle::Relative) => {
+                match self
+                    .roots
+                    .iter()
+                    .find_map(|root| path.strip_prefix(root).ok())
+                {
+                    Some(relative) if relative.as_os_str().is_empty() => {
+                        Cow::Borrowed(Path::new("."))
+                    }
+                    Some(relative) => Cow::Borrowed(relative),
+                    None => Cow::Borrowed(path),
+                }
+            }
+        }
+    }
+}
+
+impl<O: Observer> Observer for Rendered<'_, O> {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        self.observer.on_error(&self.render(path), e)
+    }
+
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.observer
+            .on_removal(&self.render(path), &self.render(package_dir), size)
+    }
+
+    fn on_scanned(&self, dir: &Path) {
+        self.observer.on_scanned(&self.render(dir))
+    }
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.observer
+            .on_would_remove(&self.render(path), &self.render(package_dir), size)
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        self.observer.on_warning(&self.render(path), message)
+    }
+
+    fn on_skipped(&self, path: &Path, reason: SkipReason) {
+        self.observer.on_skipped(&self.render(path), reason)
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        self.observer.confirm_removal(&self.render(path))
     }
+
+    fn on_measured(&self, path: &Path, size: DirSize) {
+        self.observer.on_measured(&self.render(path), size)
+    }
+
+    fn on_scan_error(&self, path: &Path, e: io::Error) {
+        self.observer.on_scan_error(&self.render(path), e)
+    }
+
+    fn should_remove(&self, path: &Path, package_dir: &Path) -> bool {
+        self.observer
+            .should_remove(&self.render(path), &self.render(package_dir))
+    }
+}
+
+/// Observer printing problems to stderr and, if `paths` is true, the paths that would be
+/// removed to stdout, terminated by NUL bytes if `print0` is true
+struct List {
+    paths: bool,
+    print0: bool,
 }
 
+impl Observer for List {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        eprintln!(
+            "{} {}: {e}",
+            style("Error:").red().for_stderr(),
+            path.display()
+        );
+    }
+
+    fn on_removal(&self, _: &Path, _: &Path, _: Option<u64>) {}
+
+    fn on_scanned(&self, _: &Path) {}
+
+    fn on_would_remove(&self, path: &Path, _: &Path, size: Option<u64>) {
+        if !self.paths {
+            return;
+        }
+        print_ignoring_errors(io::stdout().lock(), |out| {
+            if self.print0 {
+                write_nul_terminated(out, path, size)
+            } else {
+                match size {
+                    Some(size) => writeln!(out, "{}\t{size}", path.display()),
+                    None => writeln!(out, "{}", path.display()),
+                }
+            }
+        });
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        eprintln!(
+            "{} {}: {message}",
+            style("Warning:").yellow().for_stderr(),
+            path.display()
+        );
+    }
+
+    fn on_skipped(&self, _: &Path, _: SkipReason) {}
+}
+
+/// Prints to `out` through `print`, ignoring failures
+///
+/// Output stops mattering once whatever reads it is gone, and failing to print to stderr leaves
+/// nowhere to report it.
+fn print_ignoring_errors<W: Write>(mut out: W, print: impl FnOnce(&mut W) -> io::Result<()>) {
+    let _ = print(&mut out);
+}
+
+/// Writes `path`, followed by `size` after a tab if known, terminated by a NUL byte
+fn write_nul_terminated(out: &mut impl Write, path: &Path, size: Option<u64>) -> io::Result<()> {
+    // Paths are written as is since NUL bytes cannot occur in them.
+    out.write_all(path.as_os_str().as_encoded_bytes())?;
+    match size {
+        Some(size) => write!(out, "\t{size}\0"),
+        None => out.write_all(b"\0"),
+    }
+}
+
+/// Observer ignoring all notifications and removing everything
+struct Silent;
+
+impl Observer for Silent {
+    fn on_error(&self, _: &Path, _: io::Error) {}
+
+    fn on_removal(&self, _: &Path, _: &Path, _: Option<u64>) {}
+
+    fn on_scanned(&self, _: &Path) {}
+
+    fn on_would_remove(&self, _: &Path, _: &Path, _: Option<u64>) {}
+
+    fn on_warning(&self, _: &Path, _: &str) {}
+
+    fn on_skipped(&self, _: &Path, _: SkipReason) {}
+}
+
+/// Directories failing to be deleted, set from `--simulate-errors`
+#[cfg(feature = "testing")]
+static SIMULATED_ERRORS: std::sync::OnceLock<globset::GlobSet> = std::sync::OnceLock::new();
+
+/// Deletes `path` unless it matches `--simulate-errors`, failing instead
+#[cfg(feature = "testing")]
+fn failing_if_simulated(path: &Path) -> io::Result<()> {
+    match SIMULATED_ERRORS.get() {
+        Some(failing) if failing.is_match(path) => Err(io::Error::other("Simulated error")),
+        _ => cleanrust::remove_target(path),
+    }
+}
+
+/// Description of the exit codes for `--help`
+const EXIT_CODES: &str = "\
+Exit codes:
+  0    Success
+  1    Some operations failed
+  2    A directory to clean cannot be read
+  3    Invalid arguments
+  130  Interrupted";
+
+/// Name of the configuration file searched for
+const CONFIG_FILE_NAME: &str = "cleanrust.toml";
+
+/// Returns the command parsing [`Args`], with a hidden `--no-<flag>` turning off each boolean
+/// flag, e.g. one set in the configuration file
+fn command() -> clap::Command {
+    let command = Args::command();
+    let negations = command
+        .get_arguments()
+        .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+        .filter_map(|arg| {
+            let long = arg.get_long()?;
+            Some(
+                clap::Arg::new(format!("no_{}", arg.get_id()))
+                    .long(format!("no-{long}"))
+                    .action(ArgAction::SetTrue)
+                    .overrides_with(arg.get_id())
+                    .hide(true),
+            )
+        })
+        .collect::<Vec<_>>();
+    command.args(negations)
+}
+
+/// Returns the command line arguments preceded by the flags from the configuration file, if any
+///
+/// Flags set on the command line, and flags conflicting with them, are left out of the
+/// configuration so that the command line takes precedence.
+fn args_with_config() -> Result<Vec<OsString>, String> {
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+    let path = match explicit_config(args.get(1..).unwrap_or_default()) {
+        Some(path) => path,
+        None => match find_config() {
+            Some(path) => path,
+            None => return Ok(args),
+        },
+    };
+    let error = |e: &dyn Display| format!("Invalid config file {}: {e}", path.display());
+    let config = fs::read_to_string(&path).map_err(|e| error(&e))?;
+    let config = config.parse::<toml::Table>().map_err(|e| error(&e))?;
+    // Errors are reported once the configuration is merged.
+    let command_line = command().ignore_errors(true).get_matches_from(&args);
+    let flags = flags_from_config(config, &command_line).map_err(|e| error(&e))?;
+    let after_program = 1.min(args.len());
+    args.splice(after_program..after_program, flags);
+    Ok(args)
+}
+
+/// Returns the globs listed in the file at `path`, one per line, skipping blank lines and
+/// comments
+fn read_globs(path: &Path) -> Result<Vec<Glob>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            Glob::new(line).map_err(|e| format!("Invalid pattern on line {line_number}: {e}"))
+        })
+        .collect()
+}
+
+/// Returns the directories listed on stdin, one per line
+fn stdin_dirs() -> io::Result<Vec<PathBuf>> {
+    io::stdin()
+        .lines()
+        .filter_map(|line| match line {
+            Ok(line) => {
+                let line = line.trim();
+                (!line.is_empty()).then(|| Ok(PathBuf::from(line)))
+            }
+            Err(e) => Some(Err(e)),
+        })
+        .collect()
+}
+
+/// Checks that `dir` is a directory, returning it in canonical form if `canonicalize` is true
+fn root_dir(dir: &Path, canonicalize: bool) -> io::Result<PathBuf> {
+    if !fs::metadata(dir)?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            "Not a directory",
+        ));
+    }
+    if canonicalize {
+        fs::canonicalize(dir)
+    } else {
+        Ok(dir.to_owned())
+    }
+}
+
+/// Returns the path given with `--config` in `args`, if any
+fn explicit_config(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
+/// Returns the path of the configuration file found in the current directory or in the user
+/// configuration directory
+fn find_config() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    std::iter::once(PathBuf::from(CONFIG_FILE_NAME))
+        .chain(config_dir.map(|dir| dir.join(CONFIG_FILE_NAME)))
+        .find(|path| path.is_file())
+}
+
+/// Converts configuration entries to the equivalent command line flags, leaving out those set
+/// on the `command_line` or conflicting with flags set on it
+fn flags_from_config(
+    config: toml::Table,
+    command_line: &ArgMatches,
+) -> Result<Vec<OsString>, String> {
+    let command = command();
+    let set = command
+        .get_arguments()
+        .filter(|arg| {
+            command_line.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        })
+        .collect::<Vec<_>>();
+    let mut flags = Vec::new();
+    for (key, value) in config {
+        let long = key.replace('_', "-");
+        let negation = format!("no-{long}");
+        let overridden = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .is_some_and(|arg| {
+                set.iter().any(|other| {
+                    other.get_id() == arg.get_id()
+                        || other.get_long() == Some(negation.as_str())
+                        || command.get_arg_conflicts_with(arg).contains(other)
+                        || command.get_arg_conflicts_with(other).contains(&arg)
+                })
+            });
+        if overridden {
+            continue;
+        }
+        let flag = format!("--{long}");
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => flags.push(flag.clone().into()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(s) => flags.extend([flag.clone().into(), s.into()]),
+                toml::Value::Integer(n) => {
+                    flags.extend([flag.clone().into(), n.to_string().into()])
+                }
+                toml::Value::Float(x) => flags.extend([flag.clone().into(), x.to_string().into()]),
+                _ => return Err(format!("Unsupported value for `{key}`")),
+            }
+        }
+    }
+    Ok(flags)
+}
+
+/// Maximum number of concurrent jobs of each kind, beyond which threads only add overhead
+const MAX_WORKER_COUNT: usize = 1024;
+
+/// Exit code when a directory to clean cannot be read
+const UNREADABLE_ROOT_EXIT_CODE: u8 = 2;
+
+/// Exit code when arguments are invalid
+const INVALID_ARGUMENTS_EXIT_CODE: u8 = 3;
+
+/// Exit code when interrupted, following the shell convention for SIGINT
+const INTERRUPTED_EXIT_CODE: u8 = 130;
+
+/// Validates a template of the progress indicator
+fn parse_template(s: &str) -> Result<String, String> {
+    ProgressStyle::with_template(s)
+        .map(|_| s.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+/// Parses a duration made of a number and a unit (s, m, h, d or w)
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (count, unit) = s.split_at(split);
+    let count = count
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid duration `{s}`"))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Invalid duration unit in `{s}`, expected s, m, h, d or w"
+            ))
+        }
+    };
+    count
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration `{s}` is too large"))
+}
+
+/// Parses a number of concurrent jobs, capped at [`MAX_WORKER_COUNT`]
+fn parse_worker_count(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("At least one job is needed".to_owned()),
+        Ok(count) => Ok(count.min(MAX_WORKER_COUNT)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a percentage between 0 and 100
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!(
+            "Invalid percentage `{s}`, expected a number between 0 and 100"
+        )),
+    }
+}
+
+/// Parses a size in bytes with an optional binary unit (K, M, G or T)
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (count, unit) = s.split_at(split);
+    let count = count
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid size `{s}`"))?;
+    let unit = unit
+        .strip_suffix("iB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(unit);
+    let unit_bytes: u64 = match unit {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Invalid size unit in `{s}`, expected K, M, G or T")),
+    };
+    count
+        .checked_mul(unit_bytes)
+        .ok_or_else(|| format!("Size `{s}` is too large"))
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ColorChoice {
+    /// Use colors when printing to a terminal
+    Auto,
+    /// Always use colors
+    Always,
+    /// Never use colors
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// Progress spinner for humans
+    Human,
+    /// JSON document printed to stdout at the end
+    Json,
+    /// JSON object printed to stdout on a line of its own for each event as it happens
+    Ndjson,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Order {
+    /// Breadth first, directories closer to the roots first
+    Bfs,
+    /// Depth first, subdirectories before siblings
+    Dfs,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Sizes {
+    /// Number of bytes in files
+    Apparent,
+    /// Disk space allocated to files
+    Disk,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PathStyle {
+    /// Absolute paths
+    Absolute,
+    /// Paths relative to the directory to clean they are in
+    Relative,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Profile {
+    /// Development profile
+    Debug,
+    /// Release profile
+    Release,
+    /// All profiles and shared artifacts
+    All,
+}
+
+/// Prompts on the terminal to confirm removals
+///
+/// Prompts are serialized so that concurrent workers do not interleave them.
+#[derive(Debug, Default)]
+struct Confirmation {
+    yes_to_all: Mutex<bool>,
+}
+
+impl Confirmation {
+    /// Returns the prompt to use according to `args`, if any
+    fn from_args(args: &Args) -> Option<Self> {
+        (args.interactive || args.confirm_over.is_some()).then(Self::default)
+    }
+
+    fn ask(&self, path: &Path) -> bool {
+        let mut yes_to_all = self.yes_to_all.lock().unwrap();
+        if *yes_to_all {
+            return true;
+        }
+        eprint!("Remove {}? [y/N/a] ", path.display());
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() {
+            return false;
+        }
+        match answer.trim() {
+            "y" | "Y" => true,
+            "a" | "A" => {
+                *yes_to_all = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Maximum number of errors listed in the final summary
+const LISTED_ERROR_COUNT: usize = 5;
+
+/// Template of the spinner shown until the number of removals is known
+const SPINNER_TEMPLATE: &str = "{spinner} [{elapsed}] {msg}";
+
+/// Template of the progress bar shown once the number of bytes to free is known
+const PROGRESS_TEMPLATE: &str =
+    "{bar:30} {bytes}/{total_bytes} [{elapsed}] {bytes_per_sec} ETA {eta} {msg}";
+
 struct Status {
     error_count: AtomicUsize,
     removed_count: AtomicUsize,
     scanned_count: AtomicUsize,
+    skipped_count: AtomicUsize,
+    /// Number of directories that could not be scanned for lack of permissions
+    inaccessible_count: AtomicUsize,
+    /// First errors, listed in the final summary
+    first_errors: Mutex<Vec<(PathBuf, String)>>,
+    /// Number of errors by kind
+    error_kinds: Mutex<HashMap<io::ErrorKind, usize>>,
+    start: Instant,
     indicator: ProgressBar,
+    /// Style of the indicator once the number of removals is known
+    progress_style: ProgressStyle,
+    dry_run: bool,
+    quiet: bool,
+    verbosity: u8,
+    /// Whether to print removed directories sorted once done
+    sort_removals: bool,
+    /// Removed directories and their sizes, if printing them sorted once done
+    sorted_removals: Mutex<Vec<(PathBuf, Option<u64>)>>,
+    confirmation: Option<Confirmation>,
+    /// Number of packages freeing the most space to list in the final summary
+    top: Option<usize>,
+    /// Number of bytes requested to free
+    reclaim: Option<u64>,
+    /// Number of bytes to have available
+    min_free_space: Option<u64>,
+    /// Space freed by package, if listing the top packages
+    package_sizes: Mutex<HashMap<PathBuf, u64>>,
+    /// Whether to print the paths that would be removed to stdout, terminated by NUL bytes
+    print0: bool,
 }
 
 impl Status {
-    fn new() -> Self {
-        Self {
+    fn new(args: &Args) -> Result<Self, TemplateError> {
+        let spinner_template = args.template.as_deref().unwrap_or(SPINNER_TEMPLATE);
+        let progress_template = args.template.as_deref().unwrap_or(PROGRESS_TEMPLATE);
+        Ok(Self {
             error_count: Default::default(),
             removed_count: Default::default(),
             scanned_count: Default::default(),
-            indicator: ProgressBar::new_spinner()
-                .with_style(ProgressStyle::with_template("{spinner} [{elapsed}] {msg}").unwrap()),
+            skipped_count: Default::default(),
+            inaccessible_count: Default::default(),
+            first_errors: Default::default(),
+            error_kinds: Default::default(),
+            start: Instant::now(),
+            indicator: if args.quiet {
+                ProgressBar::hidden()
+            } else {
+                ProgressBar::new_spinner()
+                    .with_style(ProgressStyle::with_template(spinner_template)?)
+            },
+            progress_style: ProgressStyle::with_template(progress_template)?,
+            dry_run: args.dry_run,
+            quiet: args.quiet,
+            verbosity: args.verbose,
+            sort_removals: args.sorted_summary,
+            sorted_removals: Default::default(),
+            confirmation: Confirmation::from_args(args),
+            top: args.top,
+            reclaim: args.reclaim,
+            min_free_space: args.min_free_space,
+            package_sizes: Default::default(),
+            print0: args.print0,
+        })
+    }
+
+    fn finish(&self, summary: &Summary) {
+        self.indicator.finish();
+        let mut stderr = io::stderr().lock();
+        let mut removals = self.sorted_removals.lock().unwrap();
+        removals.sort();
+        for (path, size) in removals.iter() {
+            print_ignoring_errors(&mut stderr, |out| self.write_removal(out, path, *size));
+        }
+        if self.quiet {
+            return;
         }
+        print_ignoring_errors(&mut stderr, |out| self.write_summary(out, summary));
+    }
+
+    /// Prints the removal of `path` freeing `size` bytes, or records it to print sorted later
+    fn print_removal(&self, path: &Path, size: Option<u64>) {
+        if self.sort_removals {
+            let mut removals = self.sorted_removals.lock().unwrap();
+            removals.push((path.to_owned(), size));
+        } else {
+            self.indicator.suspend(|| {
+                print_ignoring_errors(io::stderr().lock(), |out| {
+                    self.write_removal(out, path, size)
+                })
+            });
+        }
+    }
+
+    fn write_removal(
+        &self,
+        out: &mut impl Write,
+        path: &Path,
+        size: Option<u64>,
+    ) -> io::Result<()> {
+        if !self.dry_run {
+            return writeln!(
+                out,
+                "{} {}",
+                style("removed:").green().for_stderr(),
+                path.display()
+            );
+        }
+        match size {
+            Some(size) => writeln!(
+                out,
+                "{} {} ({})",
+                style("Would remove").green().for_stderr(),
+                path.display(),
+                HumanBytes(size)
+            ),
+            None => writeln!(
+                out,
+                "{} {}",
+                style("Would remove").green().for_stderr(),
+                path.display()
+            ),
+        }
+    }
+
+    fn write_summary(&self, out: &mut impl Write, summary: &Summary) -> io::Result<()> {
+        let removed = if self.dry_run {
+            "Would remove:"
+        } else {
+            "Removed:"
+        };
+        if summary.interrupted {
+            writeln!(out, "{}", style("Interrupted").yellow().for_stderr())?;
+        }
+        if summary.timed_out {
+            writeln!(out, "{}", style("Timed out").yellow().for_stderr())?;
+        }
+        writeln!(out, "Scanned:      {} directories", summary.scanned)?;
+        writeln!(out, "{removed:13} {} target directories", summary.removed)?;
+        if summary.pinned > 0 {
+            writeln!(
+                out,
+                "Skipped:      {} ({} in use, {} pinned)",
+                summary.skipped, summary.locked, summary.pinned
+            )?;
+        } else {
+            writeln!(
+                out,
+                "Skipped:      {} ({} in use)",
+                summary.skipped, summary.locked
+            )?;
+        }
+        if summary.unprocessed > 0 {
+            writeln!(
+                out,
+                "Unprocessed:  {} target directories",
+                summary.unprocessed
+            )?;
+        }
+        if let Some(freed) = summary.freed_bytes {
+            let freed_label = if self.dry_run {
+                "Would free:"
+            } else {
+                "Freed:"
+            };
+            match (summary.removed_files, summary.removed_dirs) {
+                (Some(files), Some(dirs)) => writeln!(
+                    out,
+                    "{freed_label:13} {} in {files} files and {dirs} directories",
+                    HumanBytes(freed)
+                )?,
+                _ => writeln!(out, "{freed_label:13} {}", HumanBytes(freed))?,
+            }
+        }
+        if let Some(goal) = self.reclaim {
+            writeln!(out, "Requested:    {}", HumanBytes(goal))?;
+        }
+        if let Some(goal) = self.min_free_space {
+            let reached = if summary.free_space_reached {
+                "reached"
+            } else {
+                "not reached"
+            };
+            writeln!(out, "Available:    {} {reached}", HumanBytes(goal))?;
+        }
+        let errors = style(summary.errors).for_stderr();
+        let errors = if summary.errors == 0 {
+            errors
+        } else {
+            errors.red()
+        };
+        let mut error_kinds = self
+            .error_kinds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&kind, &count)| (kind, count))
+            .collect::<Vec<_>>();
+        error_kinds.sort_by_key(|&(kind, count)| (Reverse(count), format!("{kind:?}")));
+        if error_kinds.is_empty() {
+            writeln!(out, "Errors:       {errors}")?;
+        } else {
+            let error_kinds = error_kinds
+                .iter()
+                .map(|(kind, count)| format!("{count} {kind:?}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(out, "Errors:       {errors} ({error_kinds})")?;
+        }
+        let inaccessible = self.inaccessible_count.load(atomic::Ordering::SeqCst);
+        if inaccessible > 0 {
+            writeln!(out, "Inaccessible: {inaccessible} directories")?;
+        }
+        writeln!(out, "Elapsed:      {}", HumanDuration(self.start.elapsed()))?;
+        for (path, message) in self.first_errors.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "  {}: {message}",
+                style(path.display()).red().for_stderr()
+            )?;
+        }
+        if summary.errors > LISTED_ERROR_COUNT {
+            writeln!(out, "  ...")?;
+        }
+        if let Some(top) = self.top {
+            let mut package_sizes = self
+                .package_sizes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(package_dir, &size)| (package_dir.clone(), size))
+                .collect::<Vec<_>>();
+            package_sizes.sort_by_key(|&(_, size)| Reverse(size));
+            if !package_sizes.is_empty() {
+                writeln!(out, "Largest packages:")?;
+            }
+            for (package_dir, size) in package_sizes.iter().take(top) {
+                let size = HumanBytes(*size).to_string();
+                writeln!(out, "  {size:>10}  {}", package_dir.display())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the spinner with a progress bar of `total` bytes to free
+    fn show_progress(&self, total: u64) {
+        self.indicator.set_style(self.progress_style.clone());
+        self.indicator.set_length(total);
+        self.update();
+    }
+
+    fn record_removal(&self, package_dir: &Path, size: Option<u64>) {
+        if let (Some(_), Some(size)) = (self.top, size) {
+            let mut package_sizes = self.package_sizes.lock().unwrap();
+            *package_sizes.entry(package_dir.to_owned()).or_default() += size;
+        }
+        self.removed_count.fetch_add(1, atomic::Ordering::SeqCst);
+        self.indicator.inc(size.unwrap_or(0));
+        self.update();
     }
 
     fn update(&self) {
         let error_count = self.error_count.load(atomic::Ordering::SeqCst);
         let removed_count = self.removed_count.load(atomic::Ordering::SeqCst);
         let scanned_count = self.scanned_count.load(atomic::Ordering::SeqCst);
+        let skipped_count = self.skipped_count.load(atomic::Ordering::SeqCst);
+        let removed = if self.dry_run {
+            "would be removed"
+        } else {
+            "removed"
+        };
+        let removed = style(format!("{removed_count} {removed}"))
+            .green()
+            .for_stderr();
+        let errors = style(format!("{error_count} errors")).for_stderr();
+        let errors = if error_count == 0 {
+            errors
+        } else {
+            errors.red()
+        };
         self.indicator.set_message(format!(
-            "{scanned_count} scanned, {removed_count} removed, {error_count} errors"
+            "{scanned_count} scanned, {removed}, {skipped_count} skipped, {errors}"
         ));
     }
 }
 
 impl Observer for Status {
-    fn on_error(&self, _: io::Error) {
+    fn on_error(&self, path: &Path, e: io::Error) {
         self.error_count.fetch_add(1, atomic::Ordering::SeqCst);
+        *self
+            .error_kinds
+            .lock()
+            .unwrap()
+            .entry(e.kind())
+            .or_default() += 1;
+        let mut first_errors = self.first_errors.lock().unwrap();
+        if first_errors.len() < LISTED_ERROR_COUNT {
+            first_errors.push((path.to_owned(), e.to_string()));
+        }
+        drop(first_errors);
+        if self.quiet {
+            eprintln!(
+                "{} {}: {e}",
+                style("Error:").red().for_stderr(),
+                path.display()
+            );
+        }
         self.update();
     }
 
-    fn on_removal(&self, _: &Path) {
-        self.removed_count.fetch_add(1, atomic::Ordering::SeqCst);
-        self.update();
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        if self.verbosity >= 1 || self.sort_removals {
+            self.print_removal(path, size);
+        }
+        self.record_removal(package_dir, size);
     }
 
-    fn on_scanned(&self, _: &Path) {
+    fn on_scanned(&self, dir: &Path) {
+        if self.verbosity >= 2 {
+            self.indicator
+                .suspend(|| eprintln!("scanned: {}", dir.display()));
+        }
         self.scanned_count.fetch_add(1, atomic::Ordering::SeqCst);
         self.update();
     }
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.record_removal(package_dir, size);
+        if self.print0 {
+            print_ignoring_errors(io::stdout().lock(), |out| {
+                write_nul_terminated(out, path, size)
+            });
+        } else if !self.quiet {
+            self.print_removal(path, size);
+        }
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        if self.quiet {
+            return;
+        }
+        self.indicator.suspend(|| {
+            eprintln!(
+                "{} {}: {message}",
+                style("Warning:").yellow().for_stderr(),
+                path.display()
+            )
+        });
+    }
+
+    fn on_skipped(&self, _: &Path, _: SkipReason) {
+        self.skipped_count.fetch_add(1, atomic::Ordering::SeqCst);
+        self.update();
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        match &self.confirmation {
+            Some(confirmation) => self.indicator.suspend(|| confirmation.ask(path)),
+            None => true,
+        }
+    }
+
+    fn on_scan_error(&self, path: &Path, e: io::Error) {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            self.inaccessible_count
+                .fetch_add(1, atomic::Ordering::SeqCst);
+        }
+        self.on_error(path, e)
+    }
 }
 
-#[derive(Debug)]
-enum Job {
-    Scan(PathBuf, Sender<Job>),
-    Remove(PathBuf),
+struct JsonReport {
+    report: Mutex<Report>,
+    confirmation: Option<Confirmation>,
+}
+
+impl JsonReport {
+    fn new(args: &Args) -> Self {
+        Self {
+            confirmation: Confirmation::from_args(args),
+            report: Mutex::new(Report {
+                dry_run: args.dry_run,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn finish(self, summary: &Summary) -> io::Result<()> {
+        let report = Report {
+            interrupted: summary.interrupted,
+            timed_out: summary.timed_out,
+            totals: Totals::from(summary),
+            ..self.report.into_inner().unwrap()
+        };
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &report)?;
+        writeln!(stdout)
+    }
+
+    fn record_removal(&self, path: &Path) {
+        let mut report = self.report.lock().unwrap();
+        report.removed.push(path.to_string_lossy().into_owned());
+    }
+}
+
+impl Observer for JsonReport {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        let mut report = self.report.lock().unwrap();
+        report.errors.push(ErrorRecord {
+            path: path.to_string_lossy().into_owned(),
+            message: e.to_string(),
+        });
+    }
+
+    fn on_removal(&self, path: &Path, _: &Path, _: Option<u64>) {
+        self.record_removal(path);
+    }
+
+    fn on_scanned(&self, dir: &Path) {
+        let mut report = self.report.lock().unwrap();
+        report.scanned.push(dir.to_string_lossy().into_owned());
+    }
+
+    fn on_would_remove(&self, path: &Path, _: &Path, _: Option<u64>) {
+        self.record_removal(path);
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        self.report.lock().unwrap().warnings.push(ErrorRecord {
+            path: path.to_string_lossy().into_owned(),
+            message: message.to_owned(),
+        });
+    }
+
+    fn on_skipped(&self, path: &Path, reason: SkipReason) {
+        let mut report = self.report.lock().unwrap();
+        report.skipped.push(SkipRecord {
+            path: path.to_string_lossy().into_owned(),
+            reason: reason.to_string(),
+        });
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        match &self.confirmation {
+            Some(confirmation) => confirmation.ask(path),
+            None => true,
+        }
+    }
+}
+
+/// Runs the shell `command` with the totals of the run in environment variables
+fn run_hook(command: &str, summary: &Summary) -> io::Result<ExitStatus> {
+    let mut hook = if cfg!(windows) {
+        let mut hook = Command::new("cmd");
+        hook.arg("/C");
+        hook
+    } else {
+        let mut hook = Command::new("sh");
+        hook.arg("-c");
+        hook
+    };
+    hook.arg(command)
+        .env("CLEANRUST_REMOVED", summary.removed.to_string())
+        .env("CLEANRUST_ERRORS", summary.errors.to_string());
+    if let Some(freed) = summary.freed_bytes {
+        hook.env("CLEANRUST_FREED_BYTES", freed.to_string());
+    }
+    hook.status()
 }
 
-fn scan(
-    dir: &Path,
-    sender: Sender<Job>,
-) -> Result<impl Iterator<Item = Result<Job, io::Error>>, io::Error> {
-    let mut state = ScanState::Nothing;
-    read_dir(dir).map(|entries| {
-        entries.filter_map(move |entry| {
-            entry
-                .and_then(|entry| process_entry(&mut state, entry, &sender))
-                .transpose()
+/// Writes the totals of a run that took `elapsed` to the JSON file at `path`
+fn write_summary_json(
+    path: &Path,
+    summary: &Summary,
+    dry_run: bool,
+    elapsed: Duration,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let summary = SummaryRecord {
+        dry_run,
+        interrupted: summary.interrupted,
+        timed_out: summary.timed_out,
+        elapsed_seconds: elapsed.as_secs_f64(),
+        totals: Totals::from(summary),
+    };
+    serde_json::to_writer_pretty(&mut file, &summary)?;
+    writeln!(file)?;
+    file.flush()
+}
+
+/// Everything that happened during a run
+///
+/// In a dry run, `removed` lists the paths that would have been removed.
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    dry_run: bool,
+    interrupted: bool,
+    timed_out: bool,
+    scanned: Vec<String>,
+    removed: Vec<String>,
+    skipped: Vec<SkipRecord>,
+    errors: Vec<ErrorRecord>,
+    warnings: Vec<ErrorRecord>,
+    totals: Totals,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorRecord {
+    path: String,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SkipRecord {
+    path: String,
+    reason: String,
+}
+
+/// Totals of a run along with how it ended
+#[derive(Debug, Serialize)]
+struct SummaryRecord {
+    dry_run: bool,
+    interrupted: bool,
+    timed_out: bool,
+    elapsed_seconds: f64,
+    totals: Totals,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Totals {
+    scanned: usize,
+    removed: usize,
+    skipped: usize,
+    locked: usize,
+    pinned: usize,
+    errors: usize,
+    unprocessed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freed_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed_files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed_dirs: Option<u64>,
+}
+
+impl From<&Summary> for Totals {
+    fn from(summary: &Summary) -> Self {
+        Self {
+            scanned: summary.scanned,
+            removed: summary.removed,
+            skipped: summary.skipped,
+            locked: summary.locked,
+            pinned: summary.pinned,
+            errors: summary.errors,
+            unprocessed: summary.unprocessed,
+            freed_bytes: summary.freed_bytes,
+            removed_files: summary.removed_files,
+            removed_dirs: summary.removed_dirs,
+        }
+    }
+}
+
+/// Observer writing each event as a JSON object on a line of its own, to stdout or a socket
+struct EventStream {
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// First error writing, after which writing stops
+    error: Mutex<Option<io::Error>>,
+    dry_run: bool,
+    confirmation: Option<Confirmation>,
+}
+
+impl EventStream {
+    fn new(args: &Args) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(io::stdout())),
+            error: Mutex::new(None),
+            dry_run: args.dry_run,
+            confirmation: Confirmation::from_args(args),
+        }
+    }
+
+    /// Connects to the Unix domain socket at `path`, confirming nothing as whatever listens
+    /// cannot answer
+    #[cfg(unix)]
+    fn connect(path: &Path, args: &Args) -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(Self {
+            writer: Mutex::new(Box::new(BufWriter::new(socket))),
+            error: Mutex::new(None),
+            dry_run: args.dry_run,
+            confirmation: None,
         })
-    })
-}
-
-fn process_entry(
-    state: &mut ScanState,
-    entry: DirEntry,
-    sender: &Sender<Job>,
-) -> Result<Option<Job>, io::Error> {
-    let path = entry.path();
-    let file_type = entry.file_type()?;
-    Ok(match path.file_name() {
-        Some(name) => {
-            if name == "Cargo.toml" {
-                match state {
-                    ScanState::Nothing => {
-                        *state = ScanState::FoundCargoToml;
-                        None
-                    }
-                    ScanState::FoundCargoToml => None,
-                    ScanState::FoundTarget(target) => {
-                        let target = std::mem::take(target);
-                        *state = ScanState::FoundCargoToml;
-                        Some(Job::Remove(target))
-                    }
-                }
-            } else if file_type.is_dir() && name == "target" {
-                match state {
-                    ScanState::Nothing => {
-                        *state = ScanState::FoundTarget(path);
-                        None
-                    }
-                    ScanState::FoundCargoToml => Some(Job::Remove(path)),
-                    ScanState::FoundTarget(_) => None,
-                }
-            } else if file_type.is_dir() {
-                Some(Job::Scan(path, sender.clone()))
-            } else {
-                None
-            }
+    }
+
+    fn emit(&self, event: &Event) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_some() {
+            return;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        let result = serde_json::to_writer(&mut *writer, event)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(writer))
+            .and_then(|_| writer.flush());
+        *error = result.err();
+    }
+
+    fn finish(self, summary: &Summary) -> io::Result<()> {
+        self.emit(&Event::Summary {
+            dry_run: self.dry_run,
+            interrupted: summary.interrupted,
+            timed_out: summary.timed_out,
+            totals: Totals::from(summary),
+        });
+        match self.error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
-        None => None,
-    })
+    }
+}
+
+impl Observer for EventStream {
+    fn on_error(&self, path: &Path, e: io::Error) {
+        self.emit(&Event::Error {
+            path: path.to_string_lossy().into_owned(),
+            message: e.to_string(),
+        });
+    }
+
+    fn on_removal(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.emit(&Event::Removed {
+            path: path.to_string_lossy().into_owned(),
+            package_dir: package_dir.to_string_lossy().into_owned(),
+            size,
+        });
+    }
+
+    fn on_scanned(&self, dir: &Path) {
+        self.emit(&Event::Scanned {
+            path: dir.to_string_lossy().into_owned(),
+        });
+    }
+
+    fn on_would_remove(&self, path: &Path, package_dir: &Path, size: Option<u64>) {
+        self.emit(&Event::WouldRemove {
+            path: path.to_string_lossy().into_owned(),
+            package_dir: package_dir.to_string_lossy().into_owned(),
+            size,
+        });
+    }
+
+    fn on_warning(&self, path: &Path, message: &str) {
+        self.emit(&Event::Warning {
+            path: path.to_string_lossy().into_owned(),
+            message: message.to_owned(),
+        });
+    }
+
+    fn on_skipped(&self, path: &Path, reason: SkipReason) {
+        self.emit(&Event::Skipped {
+            path: path.to_string_lossy().into_owned(),
+            reason: reason.to_string(),
+        });
+    }
+
+    fn confirm_removal(&self, path: &Path) -> bool {
+        match &self.confirmation {
+            Some(confirmation) => confirmation.ask(path),
+            None => true,
+        }
+    }
 }
 
-#[derive(Debug)]
-enum ScanState {
-    Nothing,
-    FoundCargoToml,
-    FoundTarget(PathBuf),
+/// Line printed with `--format ndjson`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Scanned {
+        path: String,
+    },
+    Removed {
+        path: String,
+        package_dir: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+    WouldRemove {
+        path: String,
+        package_dir: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+    Skipped {
+        path: String,
+        reason: String,
+    },
+    Error {
+        path: String,
+        message: String,
+    },
+    Warning {
+        path: String,
+        message: String,
+    },
+    /// Last line, once cleaning is over
+    Summary {
+        dry_run: bool,
+        interrupted: bool,
+        timed_out: bool,
+        totals: Totals,
+    },
 }